@@ -0,0 +1,160 @@
+use actix_web::http::header::{self, HeaderMap};
+use glob::Pattern;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+struct Lists {
+    allow: Option<Vec<Pattern>>,
+    deny: Vec<Pattern>,
+}
+
+pub struct AccessLists {
+    allow_file: Option<PathBuf>,
+    deny_file: Option<PathBuf>,
+    lists: RwLock<Lists>,
+}
+
+impl AccessLists {
+    pub fn load(allow_file: Option<PathBuf>, deny_file: Option<PathBuf>) -> io::Result<Self> {
+        let lists = RwLock::new(read_lists(allow_file.as_deref(), deny_file.as_deref())?);
+        Ok(Self {
+            allow_file,
+            deny_file,
+            lists,
+        })
+    }
+
+    pub fn reload(&self) -> io::Result<()> {
+        let lists = read_lists(self.allow_file.as_deref(), self.deny_file.as_deref())?;
+        *self.lists.write().unwrap() = lists;
+        Ok(())
+    }
+
+    pub fn allow_file(&self) -> Option<&Path> {
+        self.allow_file.as_deref()
+    }
+
+    pub fn deny_file(&self) -> Option<&Path> {
+        self.deny_file.as_deref()
+    }
+
+    // The allow-list, when configured, is checked first: anything it does not
+    // match is refused even if the deny-list would not have caught it.
+    pub fn permits(&self, name: &str) -> bool {
+        let lists = self.lists.read().unwrap();
+        if let Some(allow) = &lists.allow
+            && !allow.iter().any(|x| x.matches(name))
+        {
+            return false;
+        }
+        !lists.deny.iter().any(|x| x.matches(name))
+    }
+}
+
+fn read_lists(allow_file: Option<&Path>, deny_file: Option<&Path>) -> io::Result<Lists> {
+    Ok(Lists {
+        allow: allow_file.map(read_patterns).transpose()?,
+        deny: deny_file
+            .map(read_patterns)
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+fn read_patterns(path: &Path) -> io::Result<Vec<Pattern>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| {
+            Pattern::new(x).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid pattern `{x}` in {}: {e}", path.display()),
+                )
+            })
+        })
+        .collect()
+}
+
+// Packages withdrawn after a legal takedown: one `name notice-url` per line.
+// Unlike the deny-list these are announced (451) rather than hidden.
+pub struct LegalBlocks {
+    file: Option<PathBuf>,
+    notices: RwLock<HashMap<String, String>>,
+}
+
+impl LegalBlocks {
+    pub fn load(file: Option<PathBuf>) -> io::Result<Self> {
+        let notices = RwLock::new(
+            file.as_deref()
+                .map(read_notices)
+                .transpose()?
+                .unwrap_or_default(),
+        );
+        Ok(Self { file, notices })
+    }
+
+    pub fn reload(&self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            *self.notices.write().unwrap() = read_notices(file)?;
+        }
+        Ok(())
+    }
+
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn notice(&self, name: &str) -> Option<String> {
+        self.notices.read().unwrap().get(name).cloned()
+    }
+}
+
+fn read_notices(path: &Path) -> io::Result<HashMap<String, String>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| match x.split_once(char::is_whitespace) {
+            Some((name, notice)) => Ok((name.to_string(), notice.trim().to_string())),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Missing notice URL for `{x}` in {}", path.display()),
+            )),
+        })
+        .collect()
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+}
+
+// A package without an `.acl` file is public. Otherwise the requester's
+// token has to appear on one of its lines.
+pub fn acl_permits(acl: &Path, token: Option<&str>) -> io::Result<bool> {
+    let allowed = match fs::read_to_string(acl) {
+        Ok(allowed) => allowed,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    };
+    let Some(token) = token else {
+        return Ok(false);
+    };
+    Ok(allowed
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .any(|x| constant_time_eq(x.as_bytes(), token.as_bytes())))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,308 @@
+use crate::access::{bearer_token, constant_time_eq};
+use crate::{
+    CoreData, LatestBy, ListEntry, Select, ZeroCaret, artifact_name, get_latest, package_name,
+    path_check, read_metadata, resolve, resolved_version, split_dependency, unavailable,
+    version_dirs,
+};
+use actix_web::{
+    HttpRequest, HttpResponse, error::InternalError, get, http::StatusCode, post, web,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{collections::HashSet, fs, io};
+
+#[derive(Deserialize)]
+struct GcQuery {
+    keep: Option<usize>,
+    dry_run: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct GcReport {
+    dry_run: bool,
+    removed: Vec<ListEntry>,
+}
+
+#[post("/admin/gc")]
+pub async fn gc(
+    req: HttpRequest,
+    data: web::Data<CoreData>,
+    info: web::Query<GcQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    writable(&data)?;
+    authorize(&req, &data)?;
+    // Always keep at least the newest version, or "latest" would stop resolving.
+    let keep = info.keep.unwrap_or(data.gc_keep).max(1);
+    let dry_run = info.dry_run.unwrap_or(false);
+    let removed = web::block(move || collect_garbage(&data, keep, dry_run))
+        .await?
+        .map_err(unavailable)?;
+    match serde_json::to_string(&GcReport { dry_run, removed }) {
+        Ok(body) => Ok(HttpResponse::Ok().body(body)),
+        Err(_) => Err(InternalError::new(
+            "Error serializing garbage collection report!",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into()),
+    }
+}
+
+// Stops accepting connections and lets in-flight requests finish. Only
+// registered with --enable-shutdown-endpoint, and always needs the token.
+#[post("/admin/shutdown")]
+pub async fn shutdown(
+    req: HttpRequest,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(shutdown) = &data.shutdown else {
+        return Err(InternalError::new(
+            "The shutdown endpoint is disabled on this server.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    };
+    writable(&data)?;
+    authorize(&req, &data)?;
+    shutdown.notify_one();
+    Ok(HttpResponse::Accepted().body("Shutting down."))
+}
+
+#[get("/admin/config")]
+pub async fn config(
+    req: HttpRequest,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    authorize(&req, &data)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(effective_config(&data).to_string()))
+}
+
+// Secrets are reported only as present or absent, never echoed back.
+fn effective_config(data: &CoreData) -> Value {
+    let secs = |x: Option<std::time::Duration>| x.map(|x| x.as_secs());
+    let mime_map: serde_json::Map<String, Value> = data
+        .mime_map
+        .iter()
+        .map(|(ext, mime)| (ext.clone(), Value::from(mime.to_string())))
+        .collect();
+    // One json! this size exceeds the macro recursion limit, so the config is
+    // built in sections and merged.
+    let mut effective = serde_json::Map::new();
+    let mut merge = |section: Value| {
+        if let Value::Object(section) = section {
+            effective.extend(section);
+        }
+    };
+    merge(json!({
+        "directory": data.directory.canonicalize().unwrap_or_else(|_| data.directory.clone()),
+        "port": data.port,
+        "package_cache_control": data.package_cache_control,
+        "metadata_cache_ttl": data.metadata_cache_ttl,
+        "version_cache_ttl": data.version_cache_ttl,
+        "stale_while_revalidate": data.stale_while_revalidate,
+        "stale_if_error": data.stale_if_error,
+        "count_cache_ttl": data.count_cache_ttl.as_secs(),
+        "redirect_latest": data.redirect_latest,
+        "latest_by": match data.latest_by {
+            LatestBy::Semver => "semver",
+            LatestBy::Mtime => "mtime",
+        },
+        "zero_caret": match data.zero_caret {
+            ZeroCaret::Standard => "standard",
+            ZeroCaret::Strict => "strict",
+            ZeroCaret::Loose => "loose",
+        },
+        "follow_latest_symlink": data.follow_latest_symlink,
+        "pick_first_pax": data.pick_first_pax,
+        "strip_v_prefix": data.strip_v_prefix,
+        "ci_prerelease": data.ci_prerelease,
+        "cas_dir": data.cas_dir,
+        "disable_directory_listing": data.disable_directory_listing,
+        "verify_hash": data.verify_hash,
+        "prefer_json_metadata": data.prefer_json,
+    }));
+    merge(json!({
+        "request_timeout": secs(data.request_timeout),
+        "download_timeout": secs(data.download_timeout),
+        "walk_timeout": secs(data.walk_timeout),
+        "admin_token": data.admin_token.as_ref().map(|_| "<redacted>"),
+        "webhook_url": data.webhook_url.as_ref().map(|_| "<redacted>"),
+        "gc_keep": data.gc_keep,
+        "fs_retries": data.fs_retries,
+        "quickhash_kb": data.quickhash_window / 1024,
+        "allow_list": data.access.allow_file(),
+        "deny_list": data.access.deny_file(),
+        "legal_block": data.legal.file(),
+        "banner": data.banner.file(),
+        "version_index": data.version_index.is_some(),
+        "index_file": data.registry_index.as_ref().map(|x| x.file()),
+        "resolve_cache_ttl": data.resolve_cache.as_ref().map(|x| x.ttl().as_secs()),
+        "negative_cache_ttl": data.negative_cache.as_ref().map(|x| x.ttl().as_secs()),
+    }));
+    merge(json!({
+        "artifact_pattern": data.artifact_pattern,
+        "artifact_mime": data.artifact_mime.to_string(),
+        "mime_map": mime_map,
+        "enable_acls": data.enable_acls,
+        "api_v2": data.api_v2,
+        "log_requests_body": data.log_requests_body,
+        "headers": data
+            .extra_headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {}", value.to_str().unwrap_or_default()))
+            .collect::<Vec<_>>(),
+        "max_concurrent_downloads": data.downloads.as_ref().map(|x| x.max_concurrent()),
+        "download_queue_len": data.downloads.as_ref().map(|x| x.queue_len()),
+        "max_inflight": data.inflight.as_ref().map(|x| x.max()),
+        "inflight": data.inflight.as_ref().map(|x| x.current()),
+        "metadata_schema": data.metadata_schema.is_some(),
+        "default_metadata": data.default_metadata.is_some(),
+        "shutdown_endpoint": data.shutdown.is_some(),
+        "read_only": data.read_only,
+    }));
+    Value::Object(effective)
+}
+
+// --read-only refuses anything that changes state, whatever the token.
+fn writable(data: &CoreData) -> Result<(), actix_web::Error> {
+    if data.read_only {
+        Err(InternalError::new("This server is read-only.", StatusCode::FORBIDDEN).into())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn authorize(req: &HttpRequest, data: &CoreData) -> Result<(), actix_web::Error> {
+    let Some(token) = &data.admin_token else {
+        return Err(InternalError::new(
+            "Admin endpoints are disabled on this server.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    };
+    if bearer_token(req.headers()).is_some_and(|x| constant_time_eq(x.as_bytes(), token.as_bytes()))
+    {
+        Ok(())
+    } else {
+        Err(InternalError::new("A valid admin token is required.", StatusCode::UNAUTHORIZED).into())
+    }
+}
+
+fn collect_garbage(data: &CoreData, keep: usize, dry_run: bool) -> io::Result<Vec<ListEntry>> {
+    let referenced = referenced_versions(data)?;
+    let mut removed = Vec::new();
+    for pkg in data.directory.read_dir()? {
+        let pkg = pkg?;
+        let Ok(name) = pkg.file_name().into_string() else {
+            continue;
+        };
+        if !pkg.path().is_dir() {
+            continue;
+        }
+        // Directories whose names aren't versions can't be ordered against
+        // the rest, so they are never collected.
+        let mut versions = version_dirs(&pkg.path(), data)?;
+        versions.retain(|x| x.parsed_version(data).is_some());
+        versions.sort_by_key(|x| x.version(data));
+        let excess = versions.len().saturating_sub(keep);
+        for dir in versions.into_iter().take(excess) {
+            let Ok(ver) = dir.file_name().into_string() else {
+                continue;
+            };
+            if referenced.contains(&(name.clone(), ver.clone())) {
+                continue;
+            }
+            if !dry_run {
+                fs::remove_dir_all(dir.path())?;
+                let artifact = artifact_name(&pkg.path(), &name, &ver, data);
+                if let Some(artifact) = path_check(&artifact, &pkg.path()) {
+                    match fs::remove_file(artifact) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                }
+            }
+            removed.push(ListEntry {
+                name: name.clone(),
+                version: ver,
+            });
+        }
+    }
+    Ok(removed)
+}
+
+// Versions that some package's latest metadata currently resolves a
+// dependency to. Deleting these would break installs of that package.
+fn referenced_versions(data: &CoreData) -> io::Result<HashSet<(String, String)>> {
+    let mut referenced = HashSet::new();
+    for pkg in data.directory.read_dir()? {
+        let pkg = pkg?;
+        if !pkg.path().is_dir() {
+            continue;
+        }
+        let Some(meta) = get_latest(&pkg.path(), data)?.and_then(|x| read_metadata(&x, data))
+        else {
+            continue;
+        };
+        for dep in meta
+            .runtime_dependencies
+            .iter()
+            .chain(meta.build_dependencies.iter())
+        {
+            let (dep, requested) = split_dependency(dep);
+            let (Some(name), Some(location)) =
+                (package_name(dep, data), path_check(dep, &data.directory))
+            else {
+                continue;
+            };
+            // Resolved exactly as a request for it would be, so the directory
+            // protected is the one clients are actually served.
+            let requested = Some(requested).filter(|x| !x.is_empty());
+            if let Some(ver) = resolve(&location, requested, Select::Highest, data)?
+                .and_then(|x| resolved_version(&x))
+            {
+                referenced.insert((name, ver));
+            }
+        }
+    }
+    Ok(referenced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Registry;
+
+    fn collected(data: &CoreData, keep: usize) -> Vec<String> {
+        collect_garbage(data, keep, true)
+            .unwrap()
+            .into_iter()
+            .map(|x| format!("{} {}", x.name, x.version))
+            .collect()
+    }
+
+    #[test]
+    fn gc_keeps_the_version_a_dependency_resolves_to() {
+        let registry = Registry::new();
+        for ver in ["1.2.3", "1.5.0", "1.9.0"] {
+            registry.publish("dep", ver);
+        }
+        registry.publish_with("app", "1.0.0", &["dep 1.2.3"]);
+        let data = registry.data();
+        // A bare 1.2.3 is that version, not ^1.2.3's highest 1.x.
+        assert_eq!(collected(&data, 1), ["dep 1.5.0"]);
+    }
+
+    #[test]
+    fn gc_resolves_channels_and_pins() {
+        let registry = Registry::new();
+        for ver in ["1.0.0", "1.1.0", "2.0.0", "3.0.0"] {
+            registry.publish("dep", ver);
+        }
+        fs::write(registry.0.join("dep/channels.yaml"), "stable: ^1\n").unwrap();
+        registry.publish_with("app", "1.0.0", &["dep=2.0.0", "dep stable"]);
+        let data = registry.data();
+        assert_eq!(collected(&data, 1), ["dep 1.0.0"]);
+    }
+}
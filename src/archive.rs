@@ -0,0 +1,166 @@
+use actix_web::{body::SizedStream, web::Bytes};
+use flate2::{Compression, write::GzEncoder};
+use futures_util::stream::{self, Stream, StreamExt};
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Write},
+    mem,
+    path::PathBuf,
+    time::UNIX_EPOCH,
+};
+use tar::{EntryType, Header};
+use tokio::{fs::File, io::AsyncReadExt};
+
+const BLOCK: u64 = 512;
+const CHUNK: u64 = 64 * 1024;
+
+pub struct Entry {
+    name: String,
+    path: PathBuf,
+    header: Header,
+    len: u64,
+}
+
+impl Entry {
+    // Stats the file up front so the archive's total length is known before
+    // any bytes are sent.
+    pub fn new(name: String, path: PathBuf) -> io::Result<Self> {
+        let meta = fs::metadata(&path)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        let mut header = Header::new_ustar();
+        header.set_path(&name)?;
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(meta.len());
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        Ok(Self {
+            name,
+            path,
+            header,
+            len: meta.len(),
+        })
+    }
+
+    fn archived_len(&self) -> u64 {
+        BLOCK + self.len.div_ceil(BLOCK) * BLOCK
+    }
+}
+
+struct Reading {
+    file: File,
+    remaining: u64,
+    padding: u64,
+    name: String,
+}
+
+struct State {
+    entries: VecDeque<Entry>,
+    current: Option<Reading>,
+    finished: bool,
+}
+
+// Streams a ustar archive of the given files, reading each in bounded chunks
+// as the client consumes the body. The length is exact, so clients see a
+// Content-Length and can resume or verify the download.
+pub fn tar(entries: Vec<Entry>) -> SizedStream<impl Stream<Item = io::Result<Bytes>>> {
+    let len = entries.iter().map(Entry::archived_len).sum::<u64>() + 2 * BLOCK;
+    SizedStream::new(len, tar_stream(entries))
+}
+
+// The same archive gzipped on the fly. Each tar chunk is compressed as it
+// arrives and whatever output the encoder has produced is passed on, so
+// memory stays bounded; the compressed length isn't known up front.
+pub fn tar_gz(entries: Vec<Entry>) -> impl Stream<Item = io::Result<Bytes>> {
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let state = (Box::pin(tar_stream(entries)), Some(encoder));
+    stream::unfold(state, |(mut inner, encoder)| async move {
+        let mut encoder = encoder?;
+        loop {
+            match inner.next().await {
+                Some(Ok(chunk)) => {
+                    if let Err(e) = encoder.write_all(&chunk) {
+                        return Some((Err(e), (inner, None)));
+                    }
+                    let out = mem::take(encoder.get_mut());
+                    if !out.is_empty() {
+                        return Some((Ok(Bytes::from(out)), (inner, Some(encoder))));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), (inner, None))),
+                None => return Some((encoder.finish().map(Bytes::from), (inner, None))),
+            }
+        }
+    })
+}
+
+fn tar_stream(entries: Vec<Entry>) -> impl Stream<Item = io::Result<Bytes>> {
+    let state = State {
+        entries: entries.into(),
+        current: None,
+        finished: false,
+    };
+    stream::unfold(state, next)
+}
+
+async fn next(mut state: State) -> Option<(io::Result<Bytes>, State)> {
+    if let Some(reading) = &mut state.current {
+        if reading.remaining > 0 {
+            let mut buf = vec![0; reading.remaining.min(CHUNK) as usize];
+            let res = match reading.file.read(&mut buf).await {
+                // A file truncated after it was stat'ed would leave the
+                // archive short of its declared length.
+                Ok(0) => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} shrank while being archived", reading.name),
+                )),
+                Ok(n) => {
+                    reading.remaining -= n as u64;
+                    buf.truncate(n);
+                    Ok(Bytes::from(buf))
+                }
+                Err(e) => Err(e),
+            };
+            if res.is_err() {
+                state.finished = true;
+                state.entries.clear();
+                state.current = None;
+            }
+            return Some((res, state));
+        }
+        let padding = reading.padding;
+        state.current = None;
+        if padding > 0 {
+            return Some((Ok(Bytes::from(vec![0; padding as usize])), state));
+        }
+    }
+    if let Some(entry) = state.entries.pop_front() {
+        return match File::open(&entry.path).await {
+            Ok(file) => {
+                let header = Bytes::copy_from_slice(entry.header.as_bytes());
+                state.current = Some(Reading {
+                    file,
+                    remaining: entry.len,
+                    padding: entry.len.div_ceil(BLOCK) * BLOCK - entry.len,
+                    name: entry.name,
+                });
+                Some((Ok(header), state))
+            }
+            Err(e) => {
+                state.finished = true;
+                state.entries.clear();
+                Some((Err(e), state))
+            }
+        };
+    }
+    if state.finished {
+        return None;
+    }
+    state.finished = true;
+    Some((Ok(Bytes::from(vec![0; 2 * BLOCK as usize])), state))
+}
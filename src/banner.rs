@@ -0,0 +1,34 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+// The message of the day from --banner, re-read on SIGHUP so notices can be
+// changed without a restart.
+pub struct Banner {
+    file: Option<PathBuf>,
+    text: RwLock<Option<String>>,
+}
+
+impl Banner {
+    pub fn load(file: Option<PathBuf>) -> io::Result<Self> {
+        let text = RwLock::new(file.as_deref().map(fs::read_to_string).transpose()?);
+        Ok(Self { file, text })
+    }
+
+    pub fn reload(&self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            *self.text.write().unwrap() = Some(fs::read_to_string(file)?);
+        }
+        Ok(())
+    }
+
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn text(&self) -> Option<String> {
+        self.text.read().unwrap().clone()
+    }
+}
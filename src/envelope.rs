@@ -0,0 +1,96 @@
+use actix_web::{
+    HttpResponse,
+    body::{self, BoxBody},
+    dev::ServiceResponse,
+    error::InternalError,
+    http::{
+        StatusCode,
+        header::{self, HeaderMap, HeaderValue},
+    },
+};
+use serde_json::{Value, json};
+
+// `Accept-Version: 1` asks for raw responses and `2` for the envelope, so
+// clients of either shape can share a server; without the header the
+// server-wide default (--api-v2) applies.
+pub fn wanted(headers: &HeaderMap, default: bool) -> Result<bool, actix_web::Error> {
+    let Some(value) = headers.get("Accept-Version") else {
+        return Ok(default);
+    };
+    match value.to_str().map(str::trim) {
+        Ok("1") => Ok(false),
+        Ok("2") => Ok(true),
+        _ => Err(InternalError::new(
+            "Unsupported API version requested; this server supports 1 and 2.",
+            StatusCode::NOT_ACCEPTABLE,
+        )
+        .into()),
+    }
+}
+
+// Wraps every non-download response in `{"ok", "data", "error"}`. JSON bodies
+// are embedded as-is, text (plain-text errors, `/version`) as a string.
+// Compressed, bodiless and binary responses pass through untouched, since
+// re-reading them as text would corrupt them.
+pub async fn envelope(
+    res: Result<ServiceResponse, actix_web::Error>,
+) -> Result<ServiceResponse, actix_web::Error> {
+    let res = match res {
+        Ok(res) => res,
+        // Errors raised by outer middleware never became a response, so
+        // replace them with one that renders the envelope instead.
+        Err(e) => {
+            let status = e.as_response_error().status_code();
+            let message = e.to_string();
+            let body = json!({ "ok": false, "data": null, "error": message });
+            let res = HttpResponse::build(status)
+                .content_type("application/json")
+                .body(body.to_string());
+            return Err(InternalError::from_response(message, res).into());
+        }
+    };
+    let status = res.status();
+    if res.request().path().starts_with("/package/")
+        || status.is_redirection()
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || res.headers().contains_key(header::CONTENT_ENCODING)
+        || !res.headers().get(header::CONTENT_TYPE).is_none_or(is_text)
+    {
+        return Ok(res);
+    }
+    let (req, res) = res.into_parts();
+    let (mut res, body) = res.into_parts();
+    let bytes = match body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(_) => return Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(())))),
+    };
+    let value = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(value) if value.is_object() || value.is_array() => value,
+        _ if bytes.is_empty() && !status.is_success() => {
+            Value::String(status.canonical_reason().unwrap_or_default().to_string())
+        }
+        // Untyped bodies are only known to be text once they decode as such.
+        _ => match String::from_utf8(bytes.to_vec()) {
+            Ok(text) if !text.is_empty() => Value::String(text),
+            _ => return Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(bytes)))),
+        },
+    };
+    let wrapped = if status.is_success() {
+        json!({ "ok": true, "data": value, "error": null })
+    } else {
+        json!({ "ok": false, "data": null, "error": value })
+    };
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    let res = res.set_body(BoxBody::new(wrapped.to_string()));
+    Ok(ServiceResponse::new(req, res))
+}
+
+fn is_text(content_type: &HeaderValue) -> bool {
+    let essence = content_type.to_str().unwrap_or_default();
+    let essence = essence.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/") || essence == "application/json" || essence.ends_with("+json")
+}
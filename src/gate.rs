@@ -0,0 +1,174 @@
+use actix_web::{
+    HttpResponse,
+    body::{BodySize, BoxBody, MessageBody},
+    error::InternalError,
+    http::header,
+    rt::time::timeout,
+    web::Bytes,
+};
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+const QUEUE_WAIT: Duration = Duration::from_secs(30);
+const RETRY_AFTER_SECS: u64 = 5;
+
+pub struct DownloadGate {
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    waiting: AtomicUsize,
+    queue_len: usize,
+}
+
+impl DownloadGate {
+    pub fn new(max_concurrent: usize, queue_len: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            waiting: AtomicUsize::new(0),
+            queue_len,
+        }
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue_len
+    }
+
+    pub async fn enter(&self) -> Result<OwnedSemaphorePermit, actix_web::Error> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        let _waiting = Waiting::join(&self.waiting, self.queue_len).ok_or_else(busy)?;
+        match timeout(QUEUE_WAIT, self.permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(busy()),
+        }
+    }
+}
+
+// Keeps the queue count accurate even when a waiting request is dropped
+// because the client went away or a timeout fired.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn join(waiting: &'a AtomicUsize, queue_len: usize) -> Option<Self> {
+        waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                (x < queue_len).then_some(x + 1)
+            })
+            .ok()
+            .map(|_| Self(waiting))
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn busy() -> actix_web::Error {
+    let message = "Too many concurrent downloads, please retry shortly.";
+    InternalError::from_response(
+        message,
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()))
+            .body(message),
+    )
+    .into()
+}
+
+// Global admission control: requests beyond the limit are shed with a 503
+// at once instead of queueing, so a burst can't pile up in memory. A request
+// counts until its handler returns; streamed bodies are not tracked.
+pub struct InflightGate {
+    current: AtomicUsize,
+    max: usize,
+}
+
+impl InflightGate {
+    pub fn new(max: usize) -> Self {
+        Self {
+            current: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Acquire)
+    }
+
+    pub fn enter(self: &Arc<Self>) -> Result<Inflight, actix_web::Error> {
+        self.current
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                (x < self.max).then_some(x + 1)
+            })
+            .map(|_| Inflight(self.clone()))
+            .map_err(|_| overloaded())
+    }
+}
+
+pub struct Inflight(Arc<InflightGate>);
+
+impl Drop for Inflight {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn overloaded() -> actix_web::Error {
+    let message = "Server is overloaded, please retry shortly.";
+    InternalError::from_response(
+        message,
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()))
+            .body(message),
+    )
+    .into()
+}
+
+// The permit has to outlive the handler: the file is streamed after the
+// response is returned, so it is released only once the body is dropped.
+pub fn hold(res: HttpResponse, permit: OwnedSemaphorePermit) -> HttpResponse {
+    res.map_body(|_, inner| {
+        BoxBody::new(PermitBody {
+            inner,
+            _permit: permit,
+        })
+    })
+}
+
+struct PermitBody {
+    inner: BoxBody,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl MessageBody for PermitBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_next(cx)
+    }
+}
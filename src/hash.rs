@@ -0,0 +1,84 @@
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            "blake3" => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+
+    pub fn header_name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "X-Package-Sha256",
+            Self::Sha512 => "X-Package-Sha512",
+            Self::Blake3 => "X-Package-Blake3",
+        }
+    }
+
+    pub fn digest_file(&self, path: &Path) -> io::Result<String> {
+        let mut file = fs::File::open(path)?;
+        match self {
+            Self::Sha256 => digest_reader(&mut file, Sha256::new()),
+            Self::Sha512 => digest_reader(&mut file, Sha512::new()),
+            Self::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update_reader(&mut file)?;
+                Ok(hasher.finalize().to_hex().to_string())
+            }
+        }
+    }
+}
+
+// Splits an `algorithm:digest` hash field. Bare digests predate the prefix
+// and are always SHA-256.
+pub fn split_hash(hash: &str) -> (&str, &str) {
+    hash.split_once(':').unwrap_or(("sha256", hash))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+// A cheap fingerprint over the file's size and its first and last `window`
+// bytes. It catches truncation and gross corruption, but changes in the
+// middle of the file go unnoticed and it is trivially forgeable, so it is
+// no substitute for the full digest.
+pub fn quick_fingerprint(path: &Path, window: u64) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+    let head = len.min(window);
+    io::copy(&mut (&mut file).take(head), &mut hasher)?;
+    let tail_start = len.saturating_sub(window).max(head);
+    file.seek(SeekFrom::Start(tail_start))?;
+    io::copy(&mut file.take(len - tail_start), &mut hasher)?;
+    Ok(hasher.finalize().to_hex()[..32].to_string())
+}
+
+fn digest_reader<D: Digest>(reader: &mut impl Read, mut hasher: D) -> io::Result<String> {
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
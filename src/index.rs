@@ -0,0 +1,510 @@
+use crate::{
+    CoreData, Select, VersionDir, get_latest, is_missing, metadata_file, metadata_to_json,
+    read_metadata, scan_version_dirs, split_dependency,
+};
+use actix_web::web::Bytes;
+use flate2::{Compression, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+type Stamp = Vec<(OsString, Option<SystemTime>)>;
+type Query = (String, Option<String>);
+type ResolveKey = (PathBuf, Option<String>, Select);
+
+#[derive(Default)]
+pub struct DependentsIndex {
+    stamp: Stamp,
+    dependents: HashMap<String, BTreeSet<String>>,
+}
+
+impl DependentsIndex {
+    pub fn get(&self, name: &str) -> Vec<String> {
+        self.dependents
+            .get(name)
+            .map(|x| x.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // Publishing or removing a version touches its package directory's mtime,
+    // so the stamp changes whenever the set of latest versions might have.
+    // Editing a metadata file in place does not, and is only picked up after
+    // the next directory change.
+    pub fn refresh(&mut self, data: &CoreData) -> io::Result<()> {
+        let stamp = directory_stamp(&data.directory)?;
+        if stamp == self.stamp {
+            return Ok(());
+        }
+        let mut dependents: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for pkg in data.directory.read_dir()? {
+            let pkg = pkg?;
+            let Ok(name) = pkg.file_name().into_string() else {
+                continue;
+            };
+            if !pkg.path().is_dir() {
+                continue;
+            }
+            let Some(meta) = get_latest(&pkg.path(), data)?.and_then(|x| read_metadata(&x, data))
+            else {
+                continue;
+            };
+            for dep in meta
+                .runtime_dependencies
+                .iter()
+                .chain(meta.build_dependencies.iter())
+            {
+                let (dep, _) = split_dependency(dep);
+                dependents
+                    .entry(dep.to_string())
+                    .or_default()
+                    .insert(name.clone());
+            }
+        }
+        self.stamp = stamp;
+        self.dependents = dependents;
+        Ok(())
+    }
+}
+
+fn directory_stamp(directory: &Path) -> io::Result<Stamp> {
+    let mut stamp = vec![(OsString::new(), fs::metadata(directory)?.modified().ok())];
+    for entry in directory.read_dir()? {
+        let entry = entry?;
+        let meta = fs::metadata(entry.path())?;
+        if meta.is_dir() {
+            stamp.push((entry.file_name(), meta.modified().ok()));
+        }
+    }
+    stamp.sort();
+    Ok(stamp)
+}
+
+#[derive(Default)]
+pub struct VersionIndex {
+    dirs: Mutex<HashMap<PathBuf, (SystemTime, Vec<VersionDir>)>>,
+}
+
+impl VersionIndex {
+    pub fn version_dirs(&self, path: &Path) -> io::Result<Vec<VersionDir>> {
+        let modified = match fs::metadata(path) {
+            Ok(meta) => meta.modified()?,
+            Err(e) if is_missing(&e) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        if let Some((stamp, dirs)) = self.dirs.lock().unwrap().get(path)
+            && *stamp == modified
+        {
+            return Ok(dirs.clone());
+        }
+        let dirs = scan_version_dirs(path)?;
+        // A directory changed within the filesystem's mtime granularity could
+        // change again without its mtime moving, so only trust settled ones.
+        let settled = modified.elapsed().is_ok_and(|x| x > Duration::from_secs(2));
+        if settled {
+            self.dirs
+                .lock()
+                .unwrap()
+                .insert(path.to_path_buf(), (modified, dirs.clone()));
+        }
+        Ok(dirs)
+    }
+}
+
+const RESOLVE_CACHE_CAPACITY: usize = 1024;
+
+struct Resolved {
+    stamp: SystemTime,
+    stored: Instant,
+    used: Instant,
+    path: PathBuf,
+}
+
+// Resolved metadata paths keyed by package directory, version query and
+// selection.
+// Entries expire after the TTL and are dropped as soon as the package
+// directory's mtime moves, so a newly published version is picked up on the
+// next request rather than when the entry ages out.
+pub struct ResolveCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<ResolveKey, Resolved>>,
+}
+
+impl ResolveCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn resolve(
+        &self,
+        path: &Path,
+        ver: Option<&str>,
+        select: Select,
+        lookup: impl FnOnce() -> io::Result<Option<PathBuf>>,
+    ) -> io::Result<Option<PathBuf>> {
+        let modified = match fs::metadata(path) {
+            Ok(meta) => meta.modified()?,
+            Err(e) if is_missing(&e) => return lookup(),
+            Err(e) => return Err(e),
+        };
+        let key = (path.to_path_buf(), ver.map(str::to_string), select);
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&key)
+            && entry.stamp == modified
+            && entry.stored.elapsed() < self.ttl
+        {
+            entry.used = Instant::now();
+            return Ok(Some(entry.path.clone()));
+        }
+        let resolved = lookup()?;
+        // Same reasoning as VersionIndex: only trust an mtime that has settled.
+        let settled = modified.elapsed().is_ok_and(|x| x > Duration::from_secs(2));
+        if let Some(resolved) = &resolved
+            && settled
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= RESOLVE_CACHE_CAPACITY
+                && !entries.contains_key(&key)
+                && let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, x)| x.used)
+                    .map(|(k, _)| k.clone())
+            {
+                entries.remove(&oldest);
+            }
+            let now = Instant::now();
+            entries.insert(
+                key,
+                Resolved {
+                    stamp: modified,
+                    stored: now,
+                    used: now,
+                    path: resolved.clone(),
+                },
+            );
+        }
+        Ok(resolved)
+    }
+}
+
+const NEGATIVE_CACHE_CAPACITY: usize = 4096;
+
+// Recently missed (name, version query) lookups, so clients probing for
+// optional dependencies don't cost a filesystem hit each time. There is no
+// invalidation beyond the TTL, which is why it should be kept short.
+pub struct NegativeCache {
+    ttl: Duration,
+    misses: Mutex<HashMap<Query, (Instant, &'static str)>>,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            misses: Mutex::default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn get(&self, name: &str, ver: Option<&str>) -> Option<&'static str> {
+        let key = (name.to_string(), ver.map(str::to_string));
+        let mut misses = self.misses.lock().unwrap();
+        match misses.get(&key) {
+            Some((stored, message)) if stored.elapsed() < self.ttl => Some(message),
+            Some(_) => {
+                misses.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, name: &str, ver: Option<&str>, message: &'static str) {
+        let mut misses = self.misses.lock().unwrap();
+        if misses.len() >= NEGATIVE_CACHE_CAPACITY {
+            misses.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+            if misses.len() >= NEGATIVE_CACHE_CAPACITY {
+                misses.clear();
+            }
+        }
+        let key = (name.to_string(), ver.map(str::to_string));
+        misses.insert(key, (Instant::now(), message));
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct IndexFile {
+    packages: BTreeMap<String, Vec<IndexedVersion>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexedVersion {
+    pub version: String,
+    pub modified: u64,
+    // Written out for consumers of the file, but not kept in memory: the
+    // server still reads metadata from the version directory when serving it.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    metadata: Option<Value>,
+}
+
+// A prebuilt listing of every package's versions, loaded from `--index-file`
+// so a cold start doesn't have to walk the whole tree. Rebuilding
+// (`--rebuild-index` or SIGHUP) rewrites the file; in between, a package
+// whose directory mtime has moved since it was last listed is rescanned on
+// its own, in memory only, so newly published versions are still visible.
+pub struct RegistryIndex {
+    file: PathBuf,
+    packages: RwLock<BTreeMap<String, Vec<IndexedVersion>>>,
+    stamps: Mutex<Stamps>,
+    building: AtomicBool,
+}
+
+// Package directory mtimes as of their last rescan. A package not rescanned
+// since the index was loaded or built is compared against that moment.
+struct Stamps {
+    since: SystemTime,
+    packages: HashMap<String, SystemTime>,
+}
+
+impl RegistryIndex {
+    pub fn new(file: PathBuf) -> Self {
+        Self {
+            file,
+            packages: RwLock::default(),
+            stamps: Mutex::new(Stamps {
+                since: UNIX_EPOCH,
+                packages: HashMap::new(),
+            }),
+            building: AtomicBool::new(false),
+        }
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn load_or_build(&self, data: &CoreData) -> io::Result<()> {
+        match fs::read(&self.file) {
+            Ok(bytes) => {
+                let index: IndexFile = serde_json::from_slice(&bytes)?;
+                let since = fs::metadata(&self.file)?.modified()?;
+                *self.packages.write().unwrap() = index.packages;
+                self.reset_stamps(since);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.rebuild(data),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Set for the duration of a rebuild, so listings can ask clients to come
+    // back instead of serving what is about to be replaced.
+    pub fn is_building(&self) -> bool {
+        self.building.load(Ordering::Acquire)
+    }
+
+    pub fn rebuild(&self, data: &CoreData) -> io::Result<()> {
+        self.building.store(true, Ordering::Release);
+        let res = self.build(data);
+        self.building.store(false, Ordering::Release);
+        res
+    }
+
+    fn build(&self, data: &CoreData) -> io::Result<()> {
+        let since = SystemTime::now();
+        let mut index = IndexFile::default();
+        for pkg in data.directory.read_dir()? {
+            let pkg = pkg?;
+            let Ok(name) = pkg.file_name().into_string() else {
+                continue;
+            };
+            if !pkg.path().is_dir() {
+                continue;
+            }
+            index
+                .packages
+                .insert(name, package_versions(&pkg.path(), data)?);
+        }
+        // Written beside the target and renamed over it, so a crash mid-write
+        // never leaves a truncated index for the next start to trip over.
+        let mut partial = self.file.clone().into_os_string();
+        partial.push(".partial");
+        fs::write(&partial, serde_json::to_vec(&index)?)?;
+        fs::rename(&partial, &self.file)?;
+        for versions in index.packages.values_mut() {
+            for ver in versions.iter_mut() {
+                ver.metadata = None;
+            }
+        }
+        *self.packages.write().unwrap() = index.packages;
+        self.reset_stamps(since);
+        Ok(())
+    }
+
+    fn reset_stamps(&self, since: SystemTime) {
+        let mut stamps = self.stamps.lock().unwrap();
+        stamps.since = since;
+        stamps.packages.clear();
+    }
+
+    // Rescans one package if its directory has moved since it was last
+    // listed, or if it is missing from the index altogether.
+    fn refresh(&self, name: &str, path: &Path, data: &CoreData) -> io::Result<()> {
+        let modified = match fs::metadata(path) {
+            Ok(meta) => meta.modified()?,
+            Err(e) if is_missing(&e) => UNIX_EPOCH,
+            Err(e) => return Err(e),
+        };
+        let known = self.packages.read().unwrap().contains_key(name);
+        let fresh = {
+            let stamps = self.stamps.lock().unwrap();
+            match stamps.packages.get(name) {
+                Some(stamp) => *stamp == modified,
+                None if modified == UNIX_EPOCH => !known,
+                // A change within the mtime granularity of the index itself
+                // could have landed either side of it, so only older ones are
+                // trusted.
+                None => known && modified < stamps.since,
+            }
+        };
+        if fresh {
+            return Ok(());
+        }
+        let mut versions = package_versions(path, data)?;
+        for ver in versions.iter_mut() {
+            ver.metadata = None;
+        }
+        let mut packages = self.packages.write().unwrap();
+        if modified == UNIX_EPOCH {
+            packages.remove(name);
+        } else {
+            packages.insert(name.to_string(), versions);
+        }
+        drop(packages);
+        // Same reasoning as VersionIndex: only trust an mtime that has settled.
+        let settled = modified.elapsed().is_ok_and(|x| x > Duration::from_secs(2));
+        if settled {
+            let mut stamps = self.stamps.lock().unwrap();
+            stamps.packages.insert(name.to_string(), modified);
+        }
+        Ok(())
+    }
+
+    pub fn version_dirs(&self, path: &Path, data: &CoreData) -> io::Result<Vec<VersionDir>> {
+        let Some(name) = path.file_name().and_then(|x| x.to_str()) else {
+            return Ok(Vec::new());
+        };
+        self.refresh(name, path, data)?;
+        let packages = self.packages.read().unwrap();
+        Ok(packages
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|x| VersionDir {
+                name: OsString::from(&x.version),
+                path: path.join(&x.version),
+            })
+            .collect())
+    }
+
+    // Every package currently in the registry. The root is listed each time,
+    // so packages added or removed since the build show up (or drop out)
+    // straight away; only the packages that changed are rescanned.
+    pub fn packages(&self, data: &CoreData) -> io::Result<BTreeMap<String, Vec<IndexedVersion>>> {
+        let mut present = BTreeSet::new();
+        for pkg in data.directory.read_dir()? {
+            let pkg = pkg?;
+            let Ok(name) = pkg.file_name().into_string() else {
+                continue;
+            };
+            if !pkg.path().is_dir() {
+                continue;
+            }
+            self.refresh(&name, &pkg.path(), data)?;
+            present.insert(name);
+        }
+        let mut packages = self.packages.write().unwrap();
+        packages.retain(|name, _| present.contains(name));
+        Ok(packages.clone())
+    }
+}
+
+// A package's versions as the index records them, lowest first, metadata
+// included. Versions without readable metadata are left out.
+fn package_versions(path: &Path, data: &CoreData) -> io::Result<Vec<IndexedVersion>> {
+    let mut versions = Vec::new();
+    let mut dirs = scan_version_dirs(path)?;
+    dirs.sort_by_key(|x| x.version(data));
+    for dir in dirs {
+        let (Ok(version), Some(meta_path)) = (
+            dir.file_name().into_string(),
+            metadata_file(&dir.path(), data),
+        ) else {
+            continue;
+        };
+        let Some(metadata) = metadata_to_json(&meta_path, data) else {
+            continue;
+        };
+        let modified = fs::metadata(&meta_path)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        versions.push(IndexedVersion {
+            version,
+            modified,
+            metadata: Some(metadata),
+        });
+    }
+    Ok(versions)
+}
+
+const GZIP_CACHE_CAPACITY: usize = 1024;
+
+// Gzipped metadata bodies keyed by source file, so clients accepting gzip
+// are served without compressing the same JSON on every request. An entry
+// is reused only while the source file's mtime and size are unchanged.
+#[derive(Default)]
+pub struct GzipCache {
+    bodies: Mutex<HashMap<PathBuf, (SystemTime, u64, Bytes)>>,
+}
+
+impl GzipCache {
+    pub fn get_or_compress(&self, path: &Path, body: impl FnOnce() -> String) -> io::Result<Bytes> {
+        let meta = fs::metadata(path)?;
+        let (modified, len) = (meta.modified()?, meta.len());
+        if let Some((stamp, size, gz)) = self.bodies.lock().unwrap().get(path)
+            && (*stamp, *size) == (modified, len)
+        {
+            return Ok(gz.clone());
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body().as_bytes())?;
+        let gz = Bytes::from(encoder.finish()?);
+        let mut bodies = self.bodies.lock().unwrap();
+        if bodies.len() >= GZIP_CACHE_CAPACITY && !bodies.contains_key(path) {
+            bodies.clear();
+        }
+        bodies.insert(path.to_path_buf(), (modified, len, gz.clone()));
+        Ok(gz)
+    }
+}
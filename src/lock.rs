@@ -0,0 +1,252 @@
+use crate::{
+    CoreData, Select, Version, acl_permits, blocking, index_ready, package_location, package_name,
+    path_check, read_metadata, resolve, resolved_version, split_dependency, unavailable,
+    version_satisfies,
+};
+use actix_web::{
+    HttpRequest, HttpResponse,
+    error::InternalError,
+    get,
+    http::{StatusCode, header::HeaderMap},
+    web,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize)]
+struct Locked {
+    name: String,
+    version: String,
+    hash: String,
+    #[serde(skip)]
+    required_by: Vec<(String, String)>,
+    #[serde(skip)]
+    location: PathBuf,
+}
+
+enum Failure {
+    Unresolved {
+        name: String,
+        requested: String,
+        by: String,
+    },
+    Conflict {
+        name: String,
+        locked: String,
+        requested: String,
+        by: String,
+        required_by: Vec<(String, String)>,
+    },
+}
+
+// The transitive runtime closure of one package version, each member pinned
+// to a single exact version. Every dependency is resolved to the highest
+// version its first requirement allows; a later requirement that version
+// does not satisfy is reported as a conflict rather than backtracked over.
+#[get("/packages/lock/{name}")]
+pub async fn lock(
+    req: HttpRequest,
+    name: web::Path<String>,
+    data: web::Data<CoreData>,
+    info: web::Query<Version>,
+) -> Result<HttpResponse, actix_web::Error> {
+    index_ready(&data)?;
+    let (name, ver) = (name.into_inner(), info.requested().map(String::from));
+    let root = name.clone();
+    let locked = blocking(&req, move |headers| {
+        let location = package_location(headers, &name, &data)?;
+        lock_closure(headers, &location, &name, ver.as_deref(), &data).map_err(unavailable)
+    })
+    .await?;
+    let packages = match locked {
+        Ok(packages) => packages,
+        Err(Failure::Unresolved {
+            name,
+            requested,
+            by,
+        }) => {
+            let message = format!("Dependency `{name}` of `{by}` could not be resolved.");
+            let body = json!({ "error": message, "name": name, "requested": requested, "by": by });
+            return Err(failure(message, StatusCode::NOT_FOUND, body));
+        }
+        Err(Failure::Conflict {
+            name,
+            locked,
+            requested,
+            by,
+            required_by,
+        }) => {
+            let message = format!("Dependency `{name}` is required at incompatible versions.");
+            let earlier: Vec<_> = required_by
+                .into_iter()
+                .map(|(by, requested)| json!({ "by": by, "requested": requested }))
+                .collect();
+            let body = json!({
+                "error": message,
+                "name": name,
+                "locked": locked,
+                "locked_for": earlier,
+                "conflicting": { "by": by, "requested": requested },
+            });
+            return Err(failure(message, StatusCode::CONFLICT, body));
+        }
+    };
+    let Some(version) = packages.get(&root).map(|x| x.version.clone()) else {
+        return Err(InternalError::new(
+            "Requested package's version's metadata could not be found.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    };
+    let body = json!({
+        "name": root,
+        "version": version,
+        "packages": packages.into_values().collect::<Vec<_>>(),
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string()))
+}
+
+fn failure(message: String, status: StatusCode, body: serde_json::Value) -> actix_web::Error {
+    let res = HttpResponse::build(status)
+        .content_type("application/json")
+        .body(body.to_string());
+    InternalError::from_response(message, res).into()
+}
+
+fn lock_closure(
+    headers: &HeaderMap,
+    location: &Path,
+    name: &str,
+    ver: Option<&str>,
+    data: &CoreData,
+) -> io::Result<Result<BTreeMap<String, Locked>, Failure>> {
+    let mut locked = BTreeMap::new();
+    let Some((root, deps)) = lock_one(location, name, ver, data)? else {
+        return Ok(Ok(locked));
+    };
+    let mut queue = VecDeque::from([(root.name.clone(), deps)]);
+    locked.insert(root.name.clone(), root);
+    while let Some((by, deps)) = queue.pop_front() {
+        for dep in deps {
+            let (dep_name, requested) = split_dependency(&dep);
+            if let Some(existing) = locked.get_mut(dep_name) {
+                if !satisfies(existing, requested, data)? {
+                    return Ok(Err(Failure::Conflict {
+                        name: dep_name.to_string(),
+                        locked: existing.version.clone(),
+                        requested: requested.to_string(),
+                        by,
+                        required_by: existing.required_by.clone(),
+                    }));
+                }
+                existing
+                    .required_by
+                    .push((by.clone(), requested.to_string()));
+                continue;
+            }
+            // Held to the same access lists and ACLs as a request for the
+            // dependency itself, so the lock can't leak a hidden version.
+            let dep_location = package_name(dep_name, data)
+                .filter(|x| data.access.permits(x))
+                .and_then(|_| path_check(dep_name, &data.directory))
+                .filter(|x| x.is_dir());
+            let dep_location = match dep_location {
+                Some(x) if acl_permits(headers, &x, data)? => Some(x),
+                _ => None,
+            };
+            let requested_ver = Some(requested).filter(|x| !x.is_empty());
+            let found = match dep_location {
+                Some(dep_location) => lock_one(&dep_location, dep_name, requested_ver, data)?,
+                None => None,
+            };
+            let Some((mut entry, deps)) = found else {
+                return Ok(Err(Failure::Unresolved {
+                    name: dep_name.to_string(),
+                    requested: requested.to_string(),
+                    by,
+                }));
+            };
+            entry.required_by.push((by.clone(), requested.to_string()));
+            queue.push_back((dep_name.to_string(), deps));
+            locked.insert(dep_name.to_string(), entry);
+        }
+    }
+    Ok(Ok(locked))
+}
+
+fn lock_one(
+    location: &Path,
+    name: &str,
+    ver: Option<&str>,
+    data: &CoreData,
+) -> io::Result<Option<(Locked, Vec<String>)>> {
+    let Some(meta_path) = resolve(location, ver, Select::Highest, data)? else {
+        return Ok(None);
+    };
+    let (Some(version), Some(meta)) = (
+        resolved_version(&meta_path),
+        read_metadata(&meta_path, data),
+    ) else {
+        return Ok(None);
+    };
+    let entry = Locked {
+        name: name.to_string(),
+        version,
+        hash: meta.hash,
+        required_by: Vec::new(),
+        location: location.to_path_buf(),
+    };
+    Ok(Some((entry, meta.runtime_dependencies)))
+}
+
+// Whether an already locked version also meets a later requirement, judged
+// by the resolver itself so channels, `=` pins and the version flags agree
+// with what resolving the requirement on its own would have picked from.
+fn satisfies(locked: &Locked, requested: &str, data: &CoreData) -> io::Result<bool> {
+    if requested.is_empty() || requested == locked.version {
+        return Ok(true);
+    }
+    version_satisfies(&locked.location, &locked.version, requested, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Registry;
+    use actix_web::http::header::{AUTHORIZATION, HeaderValue};
+    use std::fs;
+
+    fn locked(data: &CoreData, headers: &HeaderMap) -> Option<Vec<String>> {
+        let location = data.directory.join("app");
+        let packages = lock_closure(headers, &location, "app", None, data)
+            .unwrap()
+            .ok()?;
+        Some(packages.into_values().map(|x| x.version).collect())
+    }
+
+    #[test]
+    fn lock_respects_dependency_acls() {
+        let registry = Registry::new();
+        registry.publish("dep", "1.0.0");
+        registry.publish_with("app", "1.0.0", &["dep"]);
+        fs::write(registry.0.join("dep/.acl"), "secret\n").unwrap();
+        let data = CoreData {
+            enable_acls: true,
+            ..registry.data()
+        };
+        assert_eq!(locked(&data, &HeaderMap::new()), None);
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert_eq!(
+            locked(&data, &headers).as_deref(),
+            Some(&["1.0.0".to_string(), "1.0.0".to_string()][..])
+        );
+    }
+}
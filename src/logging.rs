@@ -0,0 +1,235 @@
+use actix_web::{
+    HttpMessage,
+    dev::{Payload, ServiceRequest},
+    error::PayloadError,
+    http::{Method, StatusCode},
+    web::Bytes,
+};
+use futures_util::Stream;
+use glob::Pattern;
+use serde_json::json;
+use std::{
+    fmt::{Display, Write},
+    pin::Pin,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn init(format: LogFormat) {
+    let _ = FORMAT.set(format);
+}
+
+fn format() -> LogFormat {
+    *FORMAT.get().unwrap_or(&LogFormat::Text)
+}
+
+pub fn info(message: impl Display) {
+    match format() {
+        LogFormat::Text => println!("{message}"),
+        LogFormat::Json => println!("{}", line("info", message)),
+    }
+}
+
+pub fn debug(message: impl Display) {
+    match format() {
+        LogFormat::Text => eprintln!("[debug] {message}"),
+        LogFormat::Json => eprintln!("{}", line("debug", message)),
+    }
+}
+
+pub fn warn(message: impl Display) {
+    match format() {
+        LogFormat::Text => eprintln!("{message}"),
+        LogFormat::Json => eprintln!("{}", line("warn", message)),
+    }
+}
+
+pub fn error(message: impl Display) {
+    match format() {
+        LogFormat::Text => eprintln!("{message}"),
+        LogFormat::Json => eprintln!("{}", line("error", message)),
+    }
+}
+
+fn line(level: &str, message: impl Display) -> serde_json::Value {
+    json!({
+        "timestamp": timestamp(),
+        "level": level,
+        "message": message.to_string(),
+    })
+}
+
+// Clients may supply their own id to correlate with their logs; otherwise
+// ids are sequential for the lifetime of the process.
+pub fn request_id(supplied: Option<&str>) -> String {
+    match supplied.filter(|x| !x.is_empty() && x.len() <= 128) {
+        Some(id) => id.to_string(),
+        None => format!("{:016x}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)),
+    }
+}
+
+// Request paths left out of the access log, typically probes. Patterns with
+// glob metacharacters are matched as globs, anything else as a prefix.
+pub enum LogIgnore {
+    Prefix(String),
+    Glob(Pattern),
+}
+
+impl LogIgnore {
+    pub fn parse(pattern: &str) -> Option<Self> {
+        if pattern.contains(['*', '?', '[']) {
+            Pattern::new(pattern).ok().map(Self::Glob)
+        } else {
+            Some(Self::Prefix(pattern.to_string()))
+        }
+    }
+
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::Prefix(String::from("/health")),
+            Self::Prefix(String::from("/ready")),
+        ]
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            Self::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            Self::Glob(pattern) => pattern.matches(path),
+        }
+    }
+}
+
+pub fn access(method: &Method, path: &str, status: StatusCode, duration: Duration, id: &str) {
+    let duration_ms = duration.as_secs_f64() * 1000.0;
+    match format() {
+        LogFormat::Text => println!(
+            "{} {method} {path} {} {duration_ms:.1}ms {id}",
+            timestamp(),
+            status.as_u16()
+        ),
+        LogFormat::Json => println!(
+            "{}",
+            json!({
+                "timestamp": timestamp(),
+                "level": "info",
+                "method": method.as_str(),
+                "path": path,
+                "status": status.as_u16(),
+                "duration_ms": (duration_ms * 1000.0).round() / 1000.0,
+                "request_id": id,
+            })
+        ),
+    }
+}
+
+const BODY_PREVIEW: usize = 32;
+
+// Debug-only (--log-requests-body): logs the request's headers with
+// credentials masked, and wraps the payload so that once it is dropped its
+// size and the first few bytes in hex are logged. Full bodies are never
+// written out.
+pub fn trace_request(req: &mut ServiceRequest, id: &str) {
+    let mut headers = String::new();
+    for (name, value) in req.headers() {
+        let sensitive = matches!(
+            name.as_str(),
+            "authorization" | "proxy-authorization" | "cookie"
+        ) || name.as_str().contains("token");
+        let value = match value.to_str() {
+            _ if sensitive => "<redacted>",
+            Ok(value) => value,
+            Err(_) => "<binary>",
+        };
+        let _ = write!(headers, " {name}={value:?}");
+    }
+    debug(format!(
+        "{id} {} {} headers:{headers}",
+        req.method(),
+        req.path()
+    ));
+    let payload = req.take_payload();
+    req.set_payload(Payload::Stream {
+        payload: Box::pin(TracedBody {
+            inner: payload,
+            id: id.to_string(),
+            len: 0,
+            head: Vec::new(),
+        }),
+    });
+}
+
+struct TracedBody {
+    inner: Payload,
+    id: String,
+    len: u64,
+    head: Vec<u8>,
+}
+
+impl Stream for TracedBody {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &res {
+            this.len += chunk.len() as u64;
+            let room = BODY_PREVIEW.saturating_sub(this.head.len());
+            this.head.extend_from_slice(&chunk[..room.min(chunk.len())]);
+        }
+        res
+    }
+}
+
+impl Drop for TracedBody {
+    fn drop(&mut self) {
+        let head: String = self.head.iter().map(|x| format!("{x:02x}")).collect();
+        let more = if self.len > self.head.len() as u64 {
+            "..."
+        } else {
+            ""
+        };
+        debug(format!(
+            "{} body: {} bytes read, head {head}{more}",
+            self.id, self.len
+        ));
+    }
+}
+
+// RFC 3339 in UTC, using Howard Hinnant's days-to-civil conversion so no
+// date crate is needed.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
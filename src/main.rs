@@ -1,30 +1,251 @@
+mod access;
+mod admin;
+mod archive;
+mod banner;
+mod envelope;
+mod gate;
+mod hash;
+mod index;
+mod lock;
+mod logging;
+mod pidfile;
+mod webhook;
+
+use access::{AccessLists, LegalBlocks};
 use actix_files::NamedFile;
 use actix_web::{
-    App, HttpResponse, HttpServer, body::BoxBody, error::InternalError, get, http::StatusCode, web,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
+    body::{BoxBody, MessageBody},
+    dev::Service,
+    error::InternalError,
+    get,
+    http::{
+        KeepAlive, StatusCode,
+        header::{self, HeaderMap},
+    },
+    middleware::{NormalizePath, TrailingSlash},
+    mime::{self, Mime},
+    routes, web,
 };
-use semver::Version as SemVer;
+use banner::Banner;
+use gate::{DownloadGate, InflightGate};
+use hash::Algorithm;
+use index::{DependentsIndex, GzipCache, NegativeCache, RegistryIndex, ResolveCache, VersionIndex};
+use logging::{LogFormat, LogIgnore};
+use pidfile::PidFile;
+use semver::{Comparator, Op, Prerelease, Version as SemVer, VersionReq};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use std::{
-    fs::{self, DirEntry},
-    io::Read,
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fs,
+    io::{self, Read},
     path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
+use tokio::sync::Notify;
 
 #[get("/packages/metadata/{name}")]
 async fn metadata(
+    req: HttpRequest,
     name: web::Path<String>,
     data: web::Data<CoreData>,
     info: web::Query<Version>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let location = if let Some(location) = path_check(&name, &data.directory) {
-        if location.is_dir() {
-            location
+    let query = req.query_string().to_owned();
+    blocking_response(&req, move |headers| {
+        metadata_response(headers, &query, &name, info.requested(), &info, &data)
+    })
+    .await
+}
+
+#[get("/packages/metadata/{name}/latest")]
+async fn metadata_latest(
+    req: HttpRequest,
+    name: web::Path<String>,
+    data: web::Data<CoreData>,
+    info: web::Query<Version>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query = req.query_string().to_owned();
+    blocking_response(&req, move |headers| {
+        metadata_response(headers, &query, &name, None, &info, &data)
+    })
+    .await
+}
+
+#[get("/packages/metadata/{name}/tags")]
+async fn package_tags(
+    req: HttpRequest,
+    name: web::Path<String>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    blocking_response(&req, move |headers| {
+        let location = package_location(headers, &name, &data)?;
+        let tags = channel_tags(&location, &data).map_err(unavailable)?;
+        match serde_json::to_string(&tags) {
+            Ok(body) => Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(body)),
+            Err(_) => Err(InternalError::new(
+                "Error serializing package tags!",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into()),
+        }
+    })
+    .await
+}
+
+// Every channel alongside the implicit `latest`, each mapped to the version
+// it currently resolves to (null when nothing matches). `latest` always
+// reports what the `/latest` route serves, even if a channel shares its name.
+fn channel_tags(path: &Path, data: &CoreData) -> io::Result<BTreeMap<String, Option<String>>> {
+    let mut tags = BTreeMap::new();
+    for (tag, query) in read_channels(path)? {
+        let found = resolve(path, Some(&query), Select::Highest, data)?;
+        tags.insert(tag, found.and_then(|x| resolved_version(&x)));
+    }
+    let latest = resolve(path, None, Select::Highest, data)?;
+    tags.insert(
+        "latest".to_string(),
+        latest.and_then(|x| resolved_version(&x)),
+    );
+    Ok(tags)
+}
+
+#[get("/packages/metadata/{name}/channels")]
+async fn metadata_channels(
+    req: HttpRequest,
+    name: web::Path<String>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    blocking_response(&req, move |headers| {
+        let location = package_location(headers, &name, &data)?;
+        let body = newest_channels(&location, &data)?;
+        Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header(VARY)
+            .body(body.to_string()))
+    })
+    .await
+}
+
+// The newest of each kind that has readable metadata; null otherwise. Held to
+// --metadata-schema like any other metadata response.
+fn newest_channels(location: &Path, data: &CoreData) -> Result<Value, actix_web::Error> {
+    let mut dirs = version_dirs(location, data).map_err(unavailable)?;
+    dirs.retain(|x| {
+        x.version_name(data)
+            .is_some_and(|x| SemVer::parse(&x).is_ok())
+    });
+    dirs.sort_by_key(|x| x.version(data));
+    let newest = |prerelease: bool| {
+        let body = dirs
+            .iter()
+            .rev()
+            .filter(|x| x.version(data).pre.is_empty() != prerelease)
+            .filter_map(|x| metadata_file(&x.path(), data))
+            .find_map(|x| metadata_to_json(&x, data));
+        if let Some(body) = &body {
+            check_schema(body, data)?;
+        }
+        Ok::<_, actix_web::Error>(body)
+    };
+    Ok(json!({ "stable": newest(false)?, "prerelease": newest(true)? }))
+}
+
+// The resolved metadata file exactly as stored, comments and formatting
+// included, for tooling that would rather not trust the JSON round-trip.
+#[get("/packages/metadata/{name}/raw")]
+async fn metadata_raw(
+    req: HttpRequest,
+    name: web::Path<String>,
+    data: web::Data<CoreData>,
+    info: web::Query<Version>,
+) -> Result<HttpResponse, actix_web::Error> {
+    blocking_response(&req, move |headers| {
+        let location = package_location(headers, &name, &data)?;
+        let select = info.select.unwrap_or_default();
+        let found = resolve(&location, info.requested(), select, &data).map_err(unavailable)?;
+        let Some(file) = found.filter(|x| x.is_file()) else {
+            return Err(InternalError::new(
+                "Requested package's version's metadata could not be found.",
+                StatusCode::NOT_FOUND,
+            )
+            .into());
+        };
+        let content_type = if file.extension().is_some_and(|x| x == "json") {
+            "application/json"
         } else {
+            "application/yaml"
+        };
+        let body = fs::read(&file).map_err(unavailable)?;
+        let mut res = HttpResponse::Ok();
+        res.content_type(content_type);
+        if let Some(ver) = resolved_version(&file) {
+            res.insert_header(("X-Resolved-Version", ver));
+        }
+        Ok(res.body(body))
+    })
+    .await
+}
+
+#[get("/packages/metadata/{name}/{ver}")]
+async fn metadata_version(
+    req: HttpRequest,
+    blocks: web::Path<(String, String)>,
+    data: web::Data<CoreData>,
+    info: web::Query<Version>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (name, ver) = blocks.into_inner();
+    let query = req.query_string().to_owned();
+    blocking_response(&req, move |headers| {
+        metadata_response(headers, &query, &name, Some(&ver), &info, &data)
+    })
+    .await
+}
+
+fn metadata_response(
+    headers: &HeaderMap,
+    query: &str,
+    name: &str,
+    ver: Option<&str>,
+    info: &Version,
+    data: &CoreData,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(name) = package_name(name, data) else {
+        return Err(InternalError::new(
+            "You do not have access to this location.",
+            StatusCode::FORBIDDEN,
+        )
+        .into());
+    };
+    let name = name.as_str();
+    check_legal(name, data)?;
+    if let Some(cache) = &data.negative_cache
+        && let Some(message) = cache.get(name, ver)
+    {
+        return Err(InternalError::new(message, StatusCode::NOT_FOUND).into());
+    }
+    let missing = |message: &'static str| -> actix_web::Error {
+        if let Some(cache) = &data.negative_cache {
+            cache.insert(name, ver, message);
+        }
+        InternalError::new(message, StatusCode::NOT_FOUND).into()
+    };
+    let location = if let Some(location) = path_check(name, &data.directory) {
+        if !data.access.permits(name) {
             return Err(InternalError::new(
                 "Requested package could not be found.",
                 StatusCode::NOT_FOUND,
             )
             .into());
+        } else if is_dir(&location).map_err(unavailable)? {
+            location
+        } else {
+            return Err(missing("Requested package could not be found."));
         }
     } else {
         return Err(InternalError::new(
@@ -33,168 +254,2537 @@ async fn metadata(
         )
         .into());
     };
-    let location = if let Some(ver) = &info.v {
-        get_version(&location, ver)
+    check_acl(headers, &location, data)?;
+    if data.disable_directory_listing
+        && version_dirs(&location, data)
+            .map_err(unavailable)?
+            .is_empty()
+    {
+        return Err(listing_disabled());
+    }
+    let select = info.select.unwrap_or_default();
+    if info.all_matching == Some(true) {
+        return all_matching(&location, ver.unwrap_or("*"), select, info.limit, data);
+    }
+    let location = resolve(&location, ver, select, data).map_err(unavailable)?;
+    let ttl = if ver.is_some_and(|x| SemVer::parse(x).is_ok()) {
+        data.version_cache_ttl
     } else {
-        get_latest(&location)
+        data.metadata_cache_ttl
     };
     if let Some(location) = location
         && location.is_file()
     {
-        match yaml_file_to_json_str(&location) {
-            Some(body) => Ok(HttpResponse::with_body(StatusCode::OK, BoxBody::new(body))),
-            None => Err(InternalError::new(
-                "Error reading package metadata!",
-                StatusCode::INTERNAL_SERVER_ERROR,
-            )
-            .into()),
+        if ver.is_none()
+            && data.redirect_latest
+            && let Some(ver) = resolved_version(&location)
+        {
+            let query = with_version(query, &ver);
+            let name = encode(name);
+            return Ok(redirect(format!("/packages/metadata/{name}?{query}")));
+        }
+        // Resolved dependencies and artifact sizes depend on more than the
+        // metadata file, so such responses are never tagged.
+        let etag = if info.resolve_deps == Some(true) || info.with_size == Some(true) {
+            None
+        } else {
+            metadata_etag(&location).map_err(unavailable)?
+        };
+        let mut cache_control = format!("public, max-age={ttl}");
+        // Lets downstream caches keep answering from a stale copy while they
+        // revalidate it against the ETag, or while this server is failing.
+        if let Some(secs) = data.stale_while_revalidate {
+            cache_control.push_str(&format!(", stale-while-revalidate={secs}"));
+        }
+        if let Some(secs) = data.stale_if_error {
+            cache_control.push_str(&format!(", stale-if-error={secs}"));
+        }
+        let cache_control = (header::CACHE_CONTROL, cache_control);
+        match metadata_to_json(&location, data) {
+            Some(mut body) => {
+                check_schema(&body, data)?;
+                // Only packages with translations vary by language; the rest
+                // are served and cached exactly as before.
+                let translated = body.get("description_i18n").is_some();
+                let language = localize(&mut body, headers);
+                let vary = if translated { VARY_LANGUAGE } else { VARY };
+                let etag = match (etag, &language) {
+                    (Some(etag), Some(language)) => Some(with_language(&etag, language)),
+                    (etag, _) => etag,
+                };
+                if let Some(etag) = &etag
+                    && if_none_match(headers, etag)
+                {
+                    let mut res = HttpResponse::NotModified();
+                    res.insert_header(cache_control)
+                        .insert_header((header::ETAG, etag.as_str()))
+                        .insert_header(vary);
+                    if let Some(language) = language {
+                        res.insert_header((header::CONTENT_LANGUAGE, language));
+                    }
+                    return Ok(res.finish());
+                }
+                if info.resolve_deps == Some(true) {
+                    resolve_dependencies(&mut body, headers, data).map_err(unavailable)?;
+                } else if info.structured_deps == Some(true) {
+                    structure_dependencies(&mut body);
+                }
+                if info.with_size == Some(true)
+                    && let Value::Object(map) = &mut body
+                {
+                    let size = artifact_size(name, &location, data).map_err(unavailable)?;
+                    map.insert("size_bytes".to_string(), json!(size));
+                }
+                // Unknown field names are ignored rather than rejected, so
+                // clients can ask for fields newer servers may add.
+                if let Some(fields) = &info.fields
+                    && let Value::Object(map) = &mut body
+                {
+                    let wanted: Vec<&str> = fields.split(',').map(str::trim).collect();
+                    map.retain(|key, _| wanted.contains(&key.as_str()));
+                }
+                let mut res = HttpResponse::Ok();
+                res.insert_header(cache_control);
+                res.insert_header(vary);
+                if let Some(language) = language {
+                    res.insert_header((header::CONTENT_LANGUAGE, language));
+                }
+                if let Some(etag) = &etag {
+                    res.insert_header((header::ETAG, etag.as_str()));
+                }
+                if let Some(ver) = resolved_version(&location) {
+                    res.insert_header(("X-Resolved-Version", ver));
+                }
+                // Only bodies derived purely from the metadata file (the
+                // tagged ones, unprojected and unrestructured) are worth
+                // keeping compressed. The envelope can't wrap a compressed
+                // body, so enveloped responses go out plain.
+                if let Some(cache) = &data.gzip_cache
+                    && etag.is_some()
+                    && info.fields.is_none()
+                    && info.structured_deps != Some(true)
+                    && !translated
+                    && accepts_gzip(headers)
+                    && matches!(envelope::wanted(headers, data.api_v2), Ok(false))
+                {
+                    let gz = cache
+                        .get_or_compress(&location, || body.to_string())
+                        .map_err(unavailable)?;
+                    res.insert_header((header::CONTENT_ENCODING, "gzip"));
+                    return Ok(res.body(gz));
+                }
+                Ok(res.body(body.to_string()))
+            }
+            None => match missing_metadata_field(&location, data) {
+                Some(field) => Err(InternalError::new(
+                    format!("Package metadata is missing the required field `{field}`."),
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                )
+                .into()),
+                None => Err(InternalError::new(
+                    "Error reading package metadata!",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+                .into()),
+            },
         }
     } else {
-        Err(InternalError::new(
+        Err(missing(
             "Requested package's version's metadata could not be found.",
-            StatusCode::NOT_FOUND,
-        )
-        .into())
+        ))
     }
 }
 
-fn get_latest(path: &Path) -> Option<PathBuf> {
-    let mut dirs = path
-        .read_dir()
-        .ok()?
-        .filter_map(|x| x.ok().filter(|x| x.path().is_dir()))
-        .collect::<Vec<DirEntry>>();
-    dirs.sort_by_key(|x| {
-        SemVer::parse(&x.file_name().to_string_lossy()).unwrap_or(SemVer::new(0, 0, 0))
-    });
-    let mut latest = dirs.last()?.path();
-    latest.push(Path::new("metadata.yaml"));
-    if latest.is_file() { Some(latest) } else { None }
+const ALL_MATCHING_LIMIT: usize = 100;
+
+// Metadata for every version a range or partial version matches, ascending.
+// Past the limit, ?select decides which end is kept (the newest by default);
+// X-Total-Count still reports every match. One entry failing
+// --metadata-schema fails the lot, as it would on its own.
+fn all_matching(
+    location: &Path,
+    ver: &str,
+    select: Select,
+    limit: Option<usize>,
+    data: &CoreData,
+) -> Result<HttpResponse, actix_web::Error> {
+    let dirs = matching_dirs(location, ver, data)
+        .map_err(unavailable)?
+        .unwrap_or_default();
+    let total = dirs.len();
+    let limit = limit.unwrap_or(ALL_MATCHING_LIMIT).min(ALL_MATCHING_LIMIT);
+    let kept = match select {
+        Select::Highest => &dirs[total.saturating_sub(limit)..],
+        Select::Lowest => &dirs[..limit.min(total)],
+    };
+    let body: Vec<Value> = kept
+        .iter()
+        .filter_map(|x| metadata_file(&x.path(), data).and_then(|x| metadata_to_json(&x, data)))
+        .collect();
+    for entry in &body {
+        check_schema(entry, data)?;
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(VARY)
+        .insert_header((X_TOTAL_COUNT, total))
+        .body(Value::Array(body).to_string()))
+}
+
+const VARY: (header::HeaderName, &str) = (header::VARY, "Accept, Accept-Encoding, Accept-Version");
+const VARY_LANGUAGE: (header::HeaderName, &str) = (
+    header::VARY,
+    "Accept, Accept-Encoding, Accept-Language, Accept-Version",
+);
+
+// Derived from the source file rather than the generated JSON so the tag is
+// stable across restarts; the extension keeps YAML- and JSON-sourced
+// representations distinct. Weak, since the body is a re-serialisation that
+// may differ byte-wise (e.g. once compressed) while meaning the same thing.
+fn metadata_etag(path: &Path) -> io::Result<Option<String>> {
+    let meta = fs::metadata(path)?;
+    let Ok(modified) = meta.modified()?.duration_since(UNIX_EPOCH) else {
+        return Ok(None);
+    };
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    Ok(Some(format!(
+        "W/\"{:x}-{:x}-{ext}\"",
+        meta.len(),
+        modified.as_nanos()
+    )))
+}
+
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(str::trim)
+        .any(|x| x == "*" || x.trim_start_matches("W/") == etag.trim_start_matches("W/"))
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|x| {
+            let mut parts = x.split(';').map(str::trim);
+            parts.next() == Some("gzip")
+                && parts.all(|x| {
+                    x.strip_prefix("q=")
+                        .is_none_or(|q| q.parse::<f32>() != Ok(0.0))
+                })
+        })
+}
+
+// The language ranges a client accepts, most preferred first.
+fn accepted_languages(headers: &HeaderMap) -> Vec<String> {
+    let mut ranges: Vec<(f32, String)> = headers
+        .get_all(header::ACCEPT_LANGUAGE)
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .filter_map(|x| {
+            let mut parts = x.split(';').map(str::trim);
+            let range = parts.next().filter(|x| !x.is_empty() && *x != "*")?;
+            let q = match parts.find_map(|x| x.strip_prefix("q=")) {
+                Some(q) => q.parse::<f32>().ok()?,
+                None => 1.0,
+            };
+            (q > 0.0).then(|| (q, range.to_ascii_lowercase()))
+        })
+        .collect();
+    ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranges.into_iter().map(|(_, x)| x).collect()
+}
+
+// Every translation is served from the same file, so the language goes into
+// the tag too; otherwise a cache holding one language could revalidate it with
+// a request for another. Only token characters are kept from the key.
+fn with_language(etag: &str, language: &str) -> String {
+    let language: String = language
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric() || *x == '-')
+        .collect();
+    let tag = etag.strip_suffix('"').unwrap_or(etag);
+    format!("{tag}-{language}\"")
+}
+
+// Swaps in the translation from `description_i18n` that best suits the
+// client and returns its language. A range takes a translation with the same
+// tag over one merely sharing its primary subtag, so `de-AT` can fall back to
+// `de` and `de` can find `de-DE`.
+fn localize(body: &mut Value, headers: &HeaderMap) -> Option<String> {
+    let Value::Object(map) = body else {
+        return None;
+    };
+    let Some(Value::Object(i18n)) = map.get("description_i18n") else {
+        return None;
+    };
+    let find = |matches: &dyn Fn(&str) -> bool| {
+        i18n.iter()
+            .find(|(tag, text)| text.is_string() && matches(&tag.to_ascii_lowercase()))
+    };
+    let (lang, text) = accepted_languages(headers).iter().find_map(|range| {
+        let primary = range.split('-').next();
+        find(&|tag| tag == range.as_str()).or_else(|| find(&|tag| tag.split('-').next() == primary))
+    })?;
+    let (lang, text) = (lang.clone(), text.clone());
+    map.insert("description".to_string(), text);
+    Some(lang)
+}
+
+fn resolve(
+    path: &Path,
+    ver: Option<&str>,
+    select: Select,
+    data: &CoreData,
+) -> io::Result<Option<PathBuf>> {
+    let lookup = || {
+        with_retries(data, || match ver {
+            Some(ver) => get_version(path, ver, select, data),
+            None => get_latest(path, data),
+        })
+    };
+    match &data.resolve_cache {
+        Some(cache) => cache.resolve(path, ver, select, lookup),
+        None => lookup(),
+    }
+}
+
+// Size of the `.pax` artifact belonging to a resolved metadata file, or None
+// when the artifact has not been uploaded alongside it.
+fn artifact_size(name: &str, meta_path: &Path, data: &CoreData) -> io::Result<Option<u64>> {
+    let (Some(ver), Some(dir)) = (
+        resolved_version(meta_path),
+        path_check(name, &data.directory),
+    ) else {
+        return Ok(None);
+    };
+    let Some(path) = path_check(&artifact_name(&dir, name, &ver, data), &dir) else {
+        return Ok(None);
+    };
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => Ok(Some(meta.len())),
+        Ok(_) => Ok(None),
+        Err(e) if is_missing(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn get_latest(path: &Path, data: &CoreData) -> io::Result<Option<PathBuf>> {
+    if data.follow_latest_symlink
+        && let Some(latest) = latest_symlink(path)?
+    {
+        return Ok(metadata_file(&latest, data));
+    }
+    let mut dirs = version_dirs(path, data)?;
+    if data.latest_by == LatestBy::Mtime {
+        return latest_by_mtime(dirs, data);
+    }
+    dirs.sort_by_key(|x| x.version(data));
+    let Some(latest) = dirs.last() else {
+        return Ok(None);
+    };
+    Ok(metadata_file(&latest.path(), data))
+}
+
+// For packages whose directory names aren't semver: the most recently
+// modified version directory that holds readable metadata wins.
+fn latest_by_mtime(dirs: Vec<VersionDir>, data: &CoreData) -> io::Result<Option<PathBuf>> {
+    let mut dated = Vec::with_capacity(dirs.len());
+    for dir in dirs {
+        match fs::metadata(dir.path()).and_then(|x| x.modified()) {
+            Ok(modified) => dated.push((modified, dir)),
+            Err(e) if is_missing(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    dated.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    Ok(dated.into_iter().find_map(|(_, dir)| {
+        metadata_file(&dir.path(), data).filter(|x| read_metadata(x, data).is_some())
+    }))
+}
+
+// A package's `channels.yaml` maps names like `stable` to version queries.
+// Only queries starting with a letter can be channels, which spares plain
+// version lookups the extra read; unknown names are left as they are.
+fn channel_query(path: &Path, ver: &str) -> io::Result<Option<String>> {
+    if !ver.starts_with(|x: char| x.is_ascii_alphabetic()) {
+        return Ok(None);
+    }
+    Ok(read_channels(path)?.remove(ver))
+}
+
+// An absent or unparsable `channels.yaml` simply defines no channels.
+fn read_channels(path: &Path) -> io::Result<HashMap<String, String>> {
+    let Some(file) = path_check("channels.yaml", path) else {
+        return Ok(HashMap::new());
+    };
+    let text = match fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) if is_missing(&e) => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(serde_norway::from_str(&text).unwrap_or_default())
 }
 
-fn get_version(path: &Path, ver: &str) -> Option<PathBuf> {
-    let dirs = path
-        .read_dir()
-        .ok()?
-        .filter_map(|x| x.ok().filter(|x| x.path().is_dir()));
+fn get_version(
+    path: &Path,
+    ver: &str,
+    select: Select,
+    data: &CoreData,
+) -> io::Result<Option<PathBuf>> {
+    let Some(dirs) = matching_dirs(path, ver, data)? else {
+        return Ok(None);
+    };
+    let chosen = match select {
+        Select::Highest => dirs.last(),
+        Select::Lowest => dirs.first(),
+    };
+    let Some(chosen) = chosen else {
+        return Ok(None);
+    };
+    Ok(metadata_file(&chosen.path(), data))
+}
+
+// Every version directory a query matches, lowest first; None when the query
+// isn't one the resolver understands.
+fn matching_dirs(path: &Path, ver: &str, data: &CoreData) -> io::Result<Option<Vec<VersionDir>>> {
+    let channel = channel_query(path, ver)?;
+    let ver = strip_v_prefix(channel.as_deref().unwrap_or(ver), data);
+    let dirs = version_dirs(path, data)?.into_iter();
     let split = ver.split('.').collect::<Vec<&str>>();
-    let dirs = match split.len() {
-        1 => Some(
+    // `=1.2.3` pins exactly that directory, whereas a bare `1.2.3` falls back
+    // to builds of it such as `1.2.3+linux` when there is no such directory.
+    let exact = ver
+        .strip_prefix('=')
+        .map(|x| strip_v_prefix(x, data))
+        .filter(|x| SemVer::parse(x).is_ok());
+    let dirs = if let Some(exact) = exact {
+        Some(
+            dirs.filter(|x| x.version_name(data).is_some_and(|x| x == exact))
+                .collect(),
+        )
+    } else if ver.contains(|x: char| "<>=^~*, ".contains(x)) {
+        VersionReq::parse(ver).ok().map(|req| {
+            let req = zero_caret(req, data.zero_caret);
             dirs.filter(|x| {
-                x.file_name()
-                    .into_string()
-                    .is_ok_and(|x| x.starts_with(&format!("{}.", split[0])))
+                x.version_name(data)
+                    .and_then(|x| SemVer::parse(&x).ok())
+                    .is_some_and(|x| req.matches(&x))
             })
-            .collect::<Vec<DirEntry>>(),
-        ),
-        2 => Some(
+            .collect()
+        })
+    } else if let Some((core, pre)) = ver.split_once('-') {
+        Some(
             dirs.filter(|x| {
-                x.file_name()
-                    .into_string()
-                    .is_ok_and(|x| x.starts_with(&format!("{}.{}.", split[0], split[1])))
+                x.version_name(data)
+                    .is_some_and(|x| prerelease_matches(&x, core, pre, data.ci_prerelease))
             })
             .collect(),
-        ),
-        3 => Some(
-            dirs.filter(|x| x.file_name().into_string().is_ok_and(|x| x == ver))
+        )
+    } else {
+        match split.len() {
+            1 => Some(
+                dirs.filter(|x| {
+                    x.version_name(data)
+                        .is_some_and(|x| x == split[0] || x.starts_with(&format!("{}.", split[0])))
+                })
+                .collect::<Vec<VersionDir>>(),
+            ),
+            2 => Some(
+                dirs.filter(|x| {
+                    x.version_name(data)
+                        .is_some_and(|x| x.starts_with(&format!("{}.{}.", split[0], split[1])))
+                })
                 .collect(),
-        ),
+            ),
+            // The exact directory when there is one; its builds only stand in
+            // for it when it's missing.
+            3 => {
+                let (exact, builds): (Vec<_>, Vec<_>) = dirs
+                    .filter(|x| {
+                        x.version_name(data)
+                            .is_some_and(|x| x == ver || x.starts_with(&format!("{ver}+")))
+                    })
+                    .partition(|x| x.version_name(data).is_some_and(|x| x == ver));
+                Some(if exact.is_empty() { builds } else { exact })
+            }
+            _ => None,
+        }
+    };
+    let Some(mut dirs) = dirs else {
+        return Ok(None);
+    };
+    dirs.sort_by_key(|x| x.version(data));
+    Ok(Some(dirs))
+}
+
+// Whether the version directory `dir` of the package at `path` is among those
+// `ver` selects, read by matching_dirs exactly as a lookup would be.
+fn version_satisfies(path: &Path, dir: &str, ver: &str, data: &CoreData) -> io::Result<bool> {
+    let dirs = with_retries(data, || matching_dirs(path, ver, data))?;
+    Ok(dirs.is_some_and(|dirs| dirs.iter().any(|x| x.name == dir)))
+}
+
+// How `^` reads below 1.0.0, taking `^0.2.3` as the example:
+// - standard: semver's own rule, `>=0.2.3, <0.3.0`.
+// - strict: only that exact version, `=0.2.3`. Carets without a patch such
+//   as `^0.2` are left as semver has them.
+// - loose: the same as for 1.x and above, `>=0.2.3, <1.0.0`.
+// `^0.0.3` is `=0.0.3` under standard and strict alike.
+fn zero_caret(req: VersionReq, mode: ZeroCaret) -> VersionReq {
+    if mode == ZeroCaret::Standard {
+        return req;
+    }
+    let comparators = req
+        .comparators
+        .into_iter()
+        .flat_map(|x| {
+            if x.op != Op::Caret || x.major != 0 {
+                return vec![x];
+            }
+            match mode {
+                ZeroCaret::Strict if x.patch.is_some() => vec![Comparator { op: Op::Exact, ..x }],
+                ZeroCaret::Loose => vec![
+                    Comparator {
+                        op: Op::GreaterEq,
+                        ..x
+                    },
+                    Comparator {
+                        op: Op::Less,
+                        major: 1,
+                        minor: None,
+                        patch: None,
+                        pre: Prerelease::EMPTY,
+                    },
+                ],
+                _ => vec![x],
+            }
+        })
+        .collect();
+    VersionReq { comparators }
+}
+
+// Under --ci-prerelease `rc1` also finds a directory named `RC1`; semver
+// itself treats prerelease identifiers case-sensitively.
+fn prerelease_matches(name: &str, core: &str, pre: &str, ci: bool) -> bool {
+    let Ok(parsed) = SemVer::parse(name) else {
+        return false;
+    };
+    let (found, pre) = if ci {
+        (
+            parsed.pre.as_str().to_ascii_lowercase(),
+            pre.to_ascii_lowercase(),
+        )
+    } else {
+        (parsed.pre.to_string(), pre.to_string())
+    };
+    format!("{}.{}.{}", parsed.major, parsed.minor, parsed.patch) == core
+        && (found == pre || found.starts_with(&format!("{pre}.")))
+}
+
+fn metadata_file(dir: &Path, data: &CoreData) -> Option<PathBuf> {
+    let names = if data.prefer_json {
+        ["metadata.json", "metadata.yaml"]
+    } else {
+        ["metadata.yaml", "metadata.json"]
+    };
+    names.iter().map(|x| dir.join(x)).find(|x| x.is_file())
+}
+
+// The directory a package segment lands on once path_check has normalised
+// it. Percent-decoding lets `secret%2F` or `.%2Fsecret` reach `secret`
+// without spelling it, so access and legal checks go by this name instead of
+// the raw segment. None for anything but a single directory under the root.
+fn package_name(name: &str, data: &CoreData) -> Option<String> {
+    let path = path_check(name, &data.directory)?;
+    let mut components = path.strip_prefix(&data.directory).ok()?.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => name.to_str().map(String::from),
         _ => None,
+    }
+}
+
+fn check_legal(name: &str, data: &CoreData) -> Result<(), actix_web::Error> {
+    let Some(notice) = data.legal.notice(name) else {
+        return Ok(());
     };
-    let dirs = if let Some(mut dirs) = dirs {
-        dirs.sort_by_key(|x| {
-            SemVer::parse(&x.file_name().to_string_lossy()).unwrap_or(SemVer::new(0, 0, 0))
-        });
-        Some(dirs)
+    let message = "Requested package is unavailable for legal reasons.";
+    let body = json!({ "error": message, "name": name, "notice": notice });
+    let res = HttpResponse::build(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS)
+        .insert_header((header::LINK, format!("<{notice}>; rel=\"blocked-by\"")))
+        .content_type("application/json")
+        .body(body.to_string());
+    Err(InternalError::from_response(message, res).into())
+}
+
+fn check_acl(
+    headers: &HeaderMap,
+    location: &Path,
+    data: &CoreData,
+) -> Result<(), actix_web::Error> {
+    if acl_permits(headers, location, data).map_err(unavailable)? {
+        Ok(())
     } else {
-        None
+        Err(InternalError::new(
+            "You do not have access to this package.",
+            StatusCode::FORBIDDEN,
+        )
+        .into())
+    }
+}
+
+// check_acl for lookups that treat a package the requester can't see as
+// absent, like dependencies, rather than refusing the whole request.
+fn acl_permits(headers: &HeaderMap, location: &Path, data: &CoreData) -> io::Result<bool> {
+    if !data.enable_acls {
+        return Ok(true);
+    }
+    match path_check(".acl", location) {
+        Some(acl) => access::acl_permits(&acl, access::bearer_token(headers)),
+        None => Ok(true),
+    }
+}
+
+fn resolved_version(path: &Path) -> Option<String> {
+    path.parent()?.file_name()?.to_str().map(String::from)
+}
+
+// The request's query string with `v` pinned to `ver`, so options such as
+// ?resolve_deps or ?fields carry over to the version a redirect points at.
+fn with_version(query: &str, ver: &str) -> String {
+    let mut pairs: Vec<String> = query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .filter(|x| !matches!(x.split('=').next(), Some("v" | "version")))
+        .map(String::from)
+        .collect();
+    // A literal `+` in build metadata would otherwise read back as a space.
+    pairs.push(format!("v={}", encode(ver)));
+    pairs.join("&")
+}
+
+// Percent-encodes everything but RFC 3986's unreserved characters, so a name
+// like `my lib` or `café` can go into a Location path segment or query value.
+fn encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn redirect(location: String) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+// An operator-maintained `latest` entry pointing at one of the package's
+// version directories. Anything resolving outside the package is ignored.
+fn latest_symlink(path: &Path) -> io::Result<Option<PathBuf>> {
+    let Some(latest) = path_check("latest", path) else {
+        return Ok(None);
+    };
+    let (pkg, latest) = match (path.canonicalize(), latest.canonicalize()) {
+        (Ok(pkg), Ok(latest)) => (pkg, latest),
+        (Err(e), _) | (_, Err(e)) if is_missing(&e) => return Ok(None),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
     };
-    let mut latest = dirs?.last()?.path();
-    latest.push(Path::new("metadata.yaml"));
-    if latest.is_file() { Some(latest) } else { None }
+    Ok((latest.parent() == Some(pkg.as_path()) && latest.is_dir()).then_some(latest))
 }
 
-#[get("/package/{name}/{ver}")]
-async fn package(
-    blocks: web::Path<(String, String)>,
-    data: web::Data<CoreData>,
-) -> Result<NamedFile, actix_web::Error> {
-    let (name, ver) = blocks.into_inner();
-    if let Some(location) = path_check(&name, &data.directory) {
-        if location.is_dir() {
-            let name = format!("{name}-{ver}.pax");
-            if let Some(file) = path_check(&name, &location) {
-                match actix_files::NamedFile::open(file.as_os_str()) {
-                    Ok(file) => return Ok(file),
-                    Err(_) => {
-                        return Err(InternalError::new(
-                            "Error reading package!",
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                        )
-                        .into());
+fn version_dirs(path: &Path, data: &CoreData) -> io::Result<Vec<VersionDir>> {
+    let mut dirs = match (&data.registry_index, &data.version_index) {
+        (Some(index), _) => index.version_dirs(path, data)?,
+        (None, Some(index)) => index.version_dirs(path)?,
+        (None, None) => scan_version_dirs(path)?,
+    };
+    if data.follow_latest_symlink {
+        dirs.retain(|x| x.name != "latest");
+    }
+    Ok(dirs)
+}
+
+fn scan_version_dirs(path: &Path) -> io::Result<Vec<VersionDir>> {
+    let entries = match path.read_dir() {
+        Ok(entries) => entries,
+        Err(e) if is_missing(&e) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            dirs.push(VersionDir {
+                name: entry.file_name(),
+                path: entry.path(),
+            });
+        }
+    }
+    Ok(dirs)
+}
+
+#[derive(Clone)]
+struct VersionDir {
+    name: OsString,
+    path: PathBuf,
+}
+
+impl VersionDir {
+    fn file_name(&self) -> OsString {
+        self.name.clone()
+    }
+
+    fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    // The name versions are matched and ordered by, which differs from the
+    // directory name only under --strip-v-prefix.
+    fn version_name(&self, data: &CoreData) -> Option<String> {
+        self.name
+            .to_str()
+            .map(|x| strip_v_prefix(x, data).to_string())
+    }
+
+    fn version(&self, data: &CoreData) -> SemVer {
+        version_key(strip_v_prefix(&self.name.to_string_lossy(), data))
+    }
+
+    fn parsed_version(&self, data: &CoreData) -> Option<SemVer> {
+        parse_version_key(strip_v_prefix(&self.name.to_string_lossy(), data))
+    }
+}
+
+// `v1.2.3` reads as `1.2.3`; a `v` not followed by a digit is part of the name.
+fn strip_v_prefix<'a>(name: &'a str, data: &CoreData) -> &'a str {
+    match name.strip_prefix('v') {
+        Some(rest) if data.strip_v_prefix && rest.starts_with(|x: char| x.is_ascii_digit()) => rest,
+        _ => name,
+    }
+}
+
+// Bare `2` or `2.1` directory names sort as `2.0.0` and `2.1.0`; anything
+// else that isn't semver sorts below every real version.
+fn version_key(name: &str) -> SemVer {
+    parse_version_key(name).unwrap_or(SemVer::new(0, 0, 0))
+}
+
+// None for names that aren't versions at all, which version_key would
+// otherwise sort below every real one.
+fn parse_version_key(name: &str) -> Option<SemVer> {
+    let padded = match name.matches('.').count() {
+        0 => format!("{name}.0.0"),
+        1 => format!("{name}.0"),
+        _ => name.to_string(),
+    };
+    SemVer::parse(name).or_else(|_| SemVer::parse(&padded)).ok()
+}
+
+fn is_dir(path: &Path) -> io::Result<bool> {
+    match fs::metadata(path) {
+        Ok(meta) => Ok(meta.is_dir()),
+        Err(e) if is_missing(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_missing(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+    )
+}
+
+// Errors a network mount can return for a moment and then recover from.
+// Missing files are a definite answer and are never retried.
+fn is_transient(err: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(
+        err.kind(),
+        Interrupted | WouldBlock | TimedOut | ResourceBusy | StaleNetworkFileHandle
+    ) || (cfg!(unix) && err.raw_os_error() == Some(5))
+}
+
+// Runs a handler's synchronous filesystem work on the blocking pool, so a
+// slow disk neither stalls the worker nor outlasts --request-timeout.
+async fn blocking_response(
+    req: &HttpRequest,
+    f: impl FnOnce(&HeaderMap) -> Result<HttpResponse, actix_web::Error> + Send + 'static,
+) -> Result<HttpResponse, actix_web::Error> {
+    let headers = req.headers().clone();
+    web::block(move || Detached::new(f(&headers)))
+        .await?
+        .attach()
+}
+
+// The same, for handlers whose blocking part yields something other than a
+// finished response, like an open file or the entries of a bundle.
+async fn blocking<T: Send + 'static>(
+    req: &HttpRequest,
+    f: impl FnOnce(&HeaderMap) -> Result<T, actix_web::Error> + Send + 'static,
+) -> Result<T, actix_web::Error> {
+    let headers = req.headers().clone();
+    match web::block(move || f(&headers).map_err(|e| Detached::new(Err(e)))).await? {
+        Ok(value) => Ok(value),
+        Err(res) => Err(res.attach().expect_err("detached from an error")),
+    }
+}
+
+// A response (or error) taken apart so it can cross back from the blocking
+// pool, which HttpResponse itself can't. Only in-memory bodies survive.
+struct Detached {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: web::Bytes,
+    error: Option<String>,
+}
+
+impl Detached {
+    fn new(res: Result<HttpResponse, actix_web::Error>) -> Self {
+        let (res, error) = match res {
+            Ok(res) => (res, None),
+            Err(e) => (e.error_response(), Some(e.to_string())),
+        };
+        let (status, headers) = (res.status(), res.headers().clone());
+        let body = res.into_body().try_into_bytes().unwrap_or_default();
+        Self {
+            status,
+            headers,
+            body,
+            error,
+        }
+    }
+
+    fn attach(self) -> Result<HttpResponse, actix_web::Error> {
+        let mut res = HttpResponse::with_body(self.status, self.body).map_into_boxed_body();
+        *res.headers_mut() = self.headers;
+        match self.error {
+            Some(message) => Err(InternalError::from_response(message, res).into()),
+            None => Ok(res),
+        }
+    }
+}
+
+// Sleeps between attempts, so it must only run on the blocking pool (inside
+// web::block), never directly in an async handler.
+fn with_retries<T>(data: &CoreData, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if attempt < data.fs_retries && is_transient(&e) => {
+                attempt += 1;
+                std::thread::sleep(Duration::from_millis(25 << attempt.min(6)));
+            }
+            res => return res,
+        }
+    }
+}
+
+const X_REQUEST_ID: header::HeaderName = header::HeaderName::from_static("x-request-id");
+const X_TOTAL_COUNT: header::HeaderName = header::HeaderName::from_static("x-total-count");
+
+// `Name: Value`, as given to --header.
+fn parse_header(arg: &str) -> Option<(header::HeaderName, header::HeaderValue)> {
+    let (name, value) = arg.split_once(':')?;
+    let name = header::HeaderName::try_from(name.trim()).ok()?;
+    let value = header::HeaderValue::from_str(value.trim()).ok()?;
+    Some((name, value))
+}
+
+fn unavailable(err: io::Error) -> actix_web::Error {
+    InternalError::new(
+        format!("Package directory is currently unavailable: {err}"),
+        StatusCode::SERVICE_UNAVAILABLE,
+    )
+    .into()
+}
+
+const REBUILD_RETRY_AFTER_SECS: u64 = 5;
+
+// Anything answered from the registry index (listings, counts, resolution
+// across the closure) waits out a rebuild rather than serve a partial index.
+fn index_ready(data: &CoreData) -> Result<(), actix_web::Error> {
+    if data
+        .registry_index
+        .as_ref()
+        .is_some_and(|x| x.is_building())
+    {
+        return Err(rebuilding());
+    }
+    Ok(())
+}
+
+fn rebuilding() -> actix_web::Error {
+    let message = "Package index is being rebuilt, please retry shortly.";
+    InternalError::from_response(
+        message,
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, REBUILD_RETRY_AFTER_SECS.to_string()))
+            .body(message),
+    )
+    .into()
+}
+
+// The `.pax` form serves the same artifact for tools that go by the URL's
+// extension; it's listed first so the extension isn't read as part of {ver}.
+#[routes]
+#[get("/package/{name}/{ver}.pax")]
+#[get("/package/{name}/{ver}")]
+async fn package(
+    req: HttpRequest,
+    blocks: web::Path<(String, String)>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (name, ver) = blocks.into_inner();
+    let pax_url = req.path().ends_with(".pax");
+    let located = {
+        let (headers, data) = (req.headers().clone(), data.clone());
+        web::block(
+            move || match locate_artifact(&headers, &name, ver, pax_url, &data) {
+                Ok(Located::Artifact(artifact)) => Ok(Ok(artifact)),
+                Ok(Located::Choices { name, ver, files }) => Ok(Err((name, ver, files))),
+                Ok(Located::Respond(res)) => Err(Detached::new(Ok(res))),
+                Err(e) => Err(Detached::new(Err(e))),
+            },
+        )
+        .await?
+    };
+    let artifact = match located {
+        Ok(Ok(artifact)) => artifact,
+        Ok(Err((name, ver, files))) => return Ok(multiple_choices(&req, &name, &ver, &files)),
+        Err(res) => return res.attach(),
+    };
+    let permit = match &data.downloads {
+        Some(gate) => Some(gate.enter().await?),
+        None => None,
+    };
+    let opened = {
+        let (path, data) = (artifact.path.clone(), data.clone());
+        web::block(move || with_retries(&data, || NamedFile::open(path.as_os_str()))).await?
+    };
+    let Ok(file) = opened else {
+        return Err(InternalError::new(
+            "Error reading package!",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into());
+    };
+    // Blobs are named by digest, so describe them by the artifact name they
+    // stand in for.
+    let file = if artifact.from_cas {
+        file.set_content_type(content_type_for(Path::new(&artifact.file_name), &data))
+            .set_content_disposition(header::ContentDisposition {
+                disposition: header::DispositionType::Attachment,
+                parameters: vec![header::DispositionParam::Filename(
+                    artifact.file_name.clone(),
+                )],
+            })
+    } else {
+        file.set_content_type(content_type_for(&artifact.path, &data))
+    };
+    let mut file = file
+        .customize()
+        .insert_header((header::CACHE_CONTROL, artifact.cache_control));
+    if let Some(meta_path) = artifact.meta_path {
+        file = file.insert_header(verify_package(meta_path, artifact.path, &data).await?);
+    }
+    let res = file.respond_to(&req).map_into_boxed_body();
+    if res.status().is_success()
+        && let Some(url) = &data.webhook_url
+    {
+        let client_ip = req.peer_addr().map(|x| x.ip());
+        webhook::download_event(url, &artifact.name, &artifact.ver, client_ip);
+    }
+    Ok(match permit {
+        Some(permit) => gate::hold(res, permit),
+        None => res,
+    })
+}
+
+// A download settled on the blocking pool, ready to be opened and served.
+struct Artifact {
+    name: String,
+    ver: String,
+    path: PathBuf,
+    file_name: String,
+    from_cas: bool,
+    cache_control: String,
+    // Set under --verify-hash: the metadata holding the expected digest.
+    meta_path: Option<PathBuf>,
+}
+
+enum Located {
+    Artifact(Artifact),
+    // Redirects, answered without opening a file.
+    Respond(HttpResponse),
+    // Several `.pax` files in the version directory and none canonical.
+    Choices {
+        name: String,
+        ver: String,
+        files: Vec<String>,
+    },
+}
+
+fn locate_artifact(
+    headers: &HeaderMap,
+    name: &str,
+    ver: String,
+    pax_url: bool,
+    data: &CoreData,
+) -> Result<Located, actix_web::Error> {
+    let Some(name) = package_name(name, data) else {
+        return Err(InternalError::new(
+            "You do not have access to this location.",
+            StatusCode::FORBIDDEN,
+        )
+        .into());
+    };
+    check_legal(&name, data)?;
+    let Some(location) = path_check(&name, &data.directory) else {
+        return Err(InternalError::new(
+            "You do not have access to this location.",
+            StatusCode::FORBIDDEN,
+        )
+        .into());
+    };
+    if !data.access.permits(&name) || !is_dir(&location).map_err(unavailable)? {
+        return Err(InternalError::new(
+            "Requested file could not be found.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    }
+    check_acl(headers, &location, data)?;
+    let mut cache_control = data.package_cache_control.clone();
+    let ver = if ver == "latest" {
+        let Some(latest) = resolve(&location, None, Select::Highest, data)
+            .map_err(unavailable)?
+            .and_then(|x| resolved_version(&x))
+        else {
+            return Err(InternalError::new(
+                "Requested package has no published versions.",
+                StatusCode::NOT_FOUND,
+            )
+            .into());
+        };
+        if data.redirect_latest {
+            let ext = if pax_url { ".pax" } else { "" };
+            return Ok(Located::Respond(redirect(format!(
+                "/package/{}/{}{ext}",
+                encode(&name),
+                encode(&latest)
+            ))));
+        }
+        cache_control = format!("public, max-age={}", data.metadata_cache_ttl);
+        latest
+    } else {
+        ver
+    };
+    let file_name = artifact_name(&location, &name, &ver, data);
+    let Some(mut path) = path_check(&file_name, &location) else {
+        return Err(
+            InternalError::new("Something went wrong.", StatusCode::INTERNAL_SERVER_ERROR).into(),
+        );
+    };
+    let mut from_cas = false;
+    if !path.is_file()
+        && let Some(blob) = path_check(&ver, &location)
+            .and_then(|x| metadata_file(&x, data))
+            .and_then(|x| cas_blob(&x, data))
+        && blob.is_file()
+    {
+        path = blob;
+        from_cas = true;
+    }
+    if !path.is_file()
+        && let Some(dir) = path_check(&ver, &location)
+    {
+        let choices = pax_files(&dir).map_err(unavailable)?;
+        match choices.first() {
+            Some(first) if data.pick_first_pax => path = first.clone(),
+            Some(_) if data.disable_directory_listing => return Err(listing_disabled()),
+            Some(_) => {
+                let files = choices
+                    .iter()
+                    .filter_map(|x| x.file_name())
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .collect();
+                return Ok(Located::Choices { name, ver, files });
+            }
+            None => {}
+        }
+    }
+    if data.disable_directory_listing && path.is_dir() {
+        return Err(listing_disabled());
+    }
+    // A version whose metadata was published without its artifact is the
+    // publisher's problem, not a server fault.
+    if let Err(e) = fs::metadata(&path)
+        && is_missing(&e)
+    {
+        return Err(InternalError::new(
+            "Requested package's artifact could not be found.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    }
+    let meta_path = if data.verify_hash {
+        let Some(meta_path) = path_check(&ver, &location).and_then(|x| metadata_file(&x, data))
+        else {
+            return Err(InternalError::new(
+                "Error reading package metadata for verification!",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into());
+        };
+        Some(meta_path)
+    } else {
+        None
+    };
+    Ok(Located::Artifact(Artifact {
+        name,
+        ver,
+        path,
+        file_name,
+        from_cas,
+        cache_control,
+        meta_path,
+    }))
+}
+
+#[routes]
+#[get("/package/{name}/{ver}/bundle")]
+#[get("/package/{name}/{ver}/bundle.tar.gz")]
+async fn bundle(
+    req: HttpRequest,
+    blocks: web::Path<(String, String)>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (name, ver) = blocks.into_inner();
+    let entries = {
+        let (data, name, ver) = (data.clone(), name.clone(), ver.clone());
+        blocking(&req, move |headers| {
+            let location = package_location(headers, &name, &data)?;
+            if !path_check(&ver, &location).is_some_and(|x| x.is_dir()) {
+                return Err(InternalError::new(
+                    "Requested package's version could not be found.",
+                    StatusCode::NOT_FOUND,
+                )
+                .into());
+            }
+            let artifact = artifact_name(&location, &name, &ver, &data);
+            if !path_check(&artifact, &location).is_some_and(|x| x.is_file()) {
+                return Err(InternalError::new(
+                    "Requested package's artifact could not be found.",
+                    StatusCode::NOT_FOUND,
+                )
+                .into());
+            }
+            version_entries(&name, &ver, &location, &data).map_err(|e| {
+                InternalError::new(
+                    format!("Error preparing bundle: {e}"),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+                .into()
+            })
+        })
+        .await?
+    };
+    let cache_control = data.package_cache_control.clone();
+    tar_download(
+        &req,
+        &data,
+        &format!("{name}-{ver}"),
+        cache_control,
+        entries,
+    )
+    .await
+}
+
+#[routes]
+#[get("/package/{name}/all")]
+#[get("/package/{name}/all.tar.gz")]
+async fn package_all(
+    req: HttpRequest,
+    name: web::Path<String>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let entries = {
+        let (data, name) = (data.clone(), name.clone());
+        blocking(&req, move |headers| {
+            let location = package_location(headers, &name, &data)?;
+            let mut dirs = version_dirs(&location, &data).map_err(unavailable)?;
+            dirs.sort_by_key(|x| x.version(&data));
+            let mut entries = Vec::new();
+            for dir in dirs {
+                if let Ok(ver) = dir.file_name().into_string() {
+                    entries.extend(
+                        version_entries(&name, &ver, &location, &data).map_err(unavailable)?,
+                    );
+                }
+            }
+            Ok(entries)
+        })
+        .await?
+    };
+    if entries.is_empty() {
+        return Err(InternalError::new(
+            "Requested package has no published versions.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    }
+    // New versions change the archive, so it is only as cacheable as "latest".
+    let cache_control = format!("public, max-age={}", data.metadata_cache_ttl);
+    tar_download(&req, &data, &name, cache_control, entries).await
+}
+
+// A version's artifact, metadata and detached signatures, whichever exist,
+// under a `{name}-{ver}/` directory in the archive.
+fn version_entries(
+    name: &str,
+    ver: &str,
+    location: &Path,
+    data: &CoreData,
+) -> io::Result<Vec<archive::Entry>> {
+    let prefix = format!("{name}-{ver}");
+    let artifact = artifact_name(location, name, ver, data);
+    let mut files = Vec::new();
+    files.extend(path_check(&artifact, location).map(|x| (artifact.clone(), x)));
+    if let Some(dir) = path_check(ver, location) {
+        for meta in ["metadata.yaml", "metadata.json"] {
+            files.push((meta.to_string(), dir.join(meta)));
+        }
+    }
+    for ext in ["sig", "asc", "minisig"] {
+        let sig = format!("{artifact}.{ext}");
+        files.push((sig.clone(), location.join(sig)));
+    }
+    let mut entries = Vec::new();
+    for (file_name, path) in files {
+        if path.is_file() {
+            entries.push(archive::Entry::new(format!("{prefix}/{file_name}"), path)?);
+        }
+    }
+    Ok(entries)
+}
+
+// Asking for `.tar.gz` gets a gzip file; otherwise clients accepting gzip get
+// the tar with a gzip Content-Encoding, and the rest a plain, sized tar.
+async fn tar_download(
+    req: &HttpRequest,
+    data: &CoreData,
+    base_name: &str,
+    cache_control: String,
+    entries: Vec<archive::Entry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let permit = match &data.downloads {
+        Some(gate) => Some(gate.enter().await?),
+        None => None,
+    };
+    let as_file = req.path().ends_with(".tar.gz");
+    let file_name = if as_file {
+        format!("{base_name}.tar.gz")
+    } else {
+        format!("{base_name}.tar")
+    };
+    let mut res = HttpResponse::Ok();
+    res.insert_header((
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{file_name}\""),
+    ))
+    .insert_header((header::CACHE_CONTROL, cache_control));
+    let res = if as_file {
+        res.content_type("application/gzip")
+            .streaming(archive::tar_gz(entries))
+    } else if accepts_gzip(req.headers()) {
+        res.content_type("application/x-tar")
+            .insert_header((header::CONTENT_ENCODING, "gzip"))
+            .insert_header((header::VARY, "Accept-Encoding"))
+            .streaming(archive::tar_gz(entries))
+    } else {
+        res.content_type("application/x-tar")
+            .insert_header((header::VARY, "Accept-Encoding"))
+            .body(archive::tar(entries))
+    };
+    Ok(match permit {
+        Some(permit) => gate::hold(res, permit),
+        None => res,
+    })
+}
+
+#[get("/package/{name}/{ver}/quickhash")]
+async fn quickhash(
+    req: HttpRequest,
+    blocks: web::Path<(String, String)>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (name, ver) = blocks.into_inner();
+    blocking_response(&req, move |headers| {
+        let location = package_location(headers, &name, &data)?;
+        let artifact = artifact_name(&location, &name, &ver, &data);
+        let Some(path) = path_check(&artifact, &location).filter(|x| x.is_file()) else {
+            return Err(InternalError::new(
+                "Requested package's artifact could not be found.",
+                StatusCode::NOT_FOUND,
+            )
+            .into());
+        };
+        let window = data.quickhash_window;
+        let fingerprint = hash::quick_fingerprint(&path, window).map_err(unavailable)?;
+        let body = json!({ "quickhash": fingerprint, "window_bytes": window });
+        Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((header::CACHE_CONTROL, data.package_cache_control.as_str()))
+            .body(body.to_string()))
+    })
+    .await
+}
+
+// One of the `.pax` files a 300 Multiple Choices offered. Only `.pax` files
+// directly inside the version directory are served.
+#[get("/package/{name}/{ver}/files/{file}")]
+async fn package_file(
+    req: HttpRequest,
+    blocks: web::Path<(String, String, String)>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (name, ver, file) = blocks.into_inner();
+    let found = {
+        let data = data.clone();
+        blocking(&req, move |headers| {
+            let location = package_location(headers, &name, &data)?;
+            let path = path_check(&ver, &location)
+                .and_then(|x| path_check(&file, &x))
+                .filter(|x| x.extension().is_some_and(|x| x == "pax") && x.is_file());
+            Ok(path.and_then(|x| with_retries(&data, || NamedFile::open(&x)).ok()))
+        })
+        .await?
+    };
+    let Some(file) = found else {
+        return Err(InternalError::new(
+            "Requested package file could not be found.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    };
+    let content_type = content_type_for(file.path(), &data);
+    Ok(file
+        .set_content_type(content_type)
+        .customize()
+        .insert_header((header::CACHE_CONTROL, data.package_cache_control.as_str()))
+        .respond_to(&req)
+        .map_into_boxed_body())
+}
+
+// Only these are served from a version directory; anything else there
+// (metadata, build scripts, artifacts) has its own route or stays private.
+const DOC_FILES: [&str; 2] = ["README.md", "CHANGELOG.md"];
+
+#[get("/packages/doc/{name}/{ver}/{file}")]
+async fn doc(
+    req: HttpRequest,
+    blocks: web::Path<(String, String, String)>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (name, ver, file) = blocks.into_inner();
+    if !DOC_FILES.contains(&file.as_str()) {
+        return Err(InternalError::new(
+            "Requested documentation file could not be found.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    }
+    let opened = {
+        let data = data.clone();
+        blocking(&req, move |headers| {
+            let location = package_location(headers, &name, &data)?;
+            let path = path_check(&ver, &location)
+                .and_then(|x| path_check(&file, &x))
+                .filter(|x| x.is_file());
+            path.map(|x| NamedFile::open(x).map_err(unavailable))
+                .transpose()
+        })
+        .await?
+    };
+    let Some(file) = opened else {
+        return Err(InternalError::new(
+            "Requested documentation file could not be found.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    };
+    let markdown = "text/markdown; charset=utf-8"
+        .parse()
+        .unwrap_or(mime::TEXT_PLAIN);
+    Ok(file
+        .set_content_type(markdown)
+        .disable_content_disposition()
+        .customize()
+        .insert_header((
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", data.version_cache_ttl),
+        ))
+        .respond_to(&req)
+        .map_into_boxed_body())
+}
+
+// The access, existence and ACL checks every package-scoped route starts with.
+fn package_location(
+    headers: &HeaderMap,
+    name: &str,
+    data: &CoreData,
+) -> Result<PathBuf, actix_web::Error> {
+    let Some(name) = package_name(name, data) else {
+        return Err(InternalError::new(
+            "You do not have access to this location.",
+            StatusCode::FORBIDDEN,
+        )
+        .into());
+    };
+    let name = name.as_str();
+    check_legal(name, data)?;
+    let Some(location) = path_check(name, &data.directory) else {
+        return Err(InternalError::new(
+            "You do not have access to this location.",
+            StatusCode::FORBIDDEN,
+        )
+        .into());
+    };
+    if !data.access.permits(name) || !is_dir(&location).map_err(unavailable)? {
+        return Err(InternalError::new(
+            "Requested package could not be found.",
+            StatusCode::NOT_FOUND,
+        )
+        .into());
+    }
+    check_acl(headers, &location, data)?;
+    Ok(location)
+}
+
+// A package's `artifact.pattern` file overrides --artifact-pattern for its
+// artifacts; `{name}` and `{ver}` are substituted, and callers still pass the
+// result through path_check. An unreadable pattern file is ignored.
+fn artifact_name(location: &Path, name: &str, ver: &str, data: &CoreData) -> String {
+    let pattern = path_check("artifact.pattern", location)
+        .and_then(|x| fs::read_to_string(x).ok())
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty());
+    pattern
+        .as_deref()
+        .unwrap_or(&data.artifact_pattern)
+        .replace("{name}", name)
+        .replace("{ver}", ver)
+}
+
+// Artifacts that don't follow the `{name}-{ver}.pax` convention, found inside
+// the version directory itself, in lexical order.
+fn pax_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(e) if is_missing(&e) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|x| x == "pax") && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// With --disable-directory-listing, anything that would expose a directory's
+// contents rather than a curated package/version view is refused outright.
+fn listing_disabled() -> actix_web::Error {
+    InternalError::new(
+        "Directory listings are disabled on this server.",
+        StatusCode::FORBIDDEN,
+    )
+    .into()
+}
+
+// Each choice as an absolute URI on the files route, so clients can follow
+// whichever they pick.
+fn multiple_choices(req: &HttpRequest, name: &str, ver: &str, files: &[String]) -> HttpResponse {
+    let info = req.connection_info();
+    let base = format!(
+        "{}://{}/package/{}/{}/files",
+        info.scheme(),
+        info.host(),
+        encode(name),
+        encode(ver)
+    );
+    let uris: Vec<_> = files
+        .iter()
+        .map(|x| format!("{base}/{}", encode(x)))
+        .collect();
+    HttpResponse::MultipleChoices()
+        .content_type("application/json")
+        .body(json!(uris).to_string())
+}
+
+// Under --cas-dir, artifacts are stored once per content at
+// `{cas-dir}/{algorithm}/{digest}`, keyed by the version's metadata `hash`.
+fn cas_blob(meta_path: &Path, data: &CoreData) -> Option<PathBuf> {
+    let cas_dir = data.cas_dir.as_deref()?;
+    let meta = read_metadata(meta_path, data)?;
+    let (algorithm, digest) = hash::split_hash(&meta.hash);
+    Algorithm::parse(algorithm)?;
+    let blob = format!(
+        "{}/{}",
+        algorithm.to_ascii_lowercase(),
+        digest.to_ascii_lowercase()
+    );
+    path_check(&blob, cas_dir)
+}
+
+fn content_type_for(path: &Path, data: &CoreData) -> Mime {
+    let ext = path
+        .extension()
+        .map(|x| x.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match data.mime_map.get(&ext) {
+        Some(mime) => mime.clone(),
+        None if ext == "pax" => data.artifact_mime.clone(),
+        None => mime::APPLICATION_OCTET_STREAM,
+    }
+}
+
+fn read_mime_map(path: &Path) -> io::Result<HashMap<String, Mime>> {
+    let mut map = HashMap::new();
+    for line in fs::read_to_string(path)?.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = line.split_once('=').and_then(|(ext, mime)| {
+            let ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+            Some((ext, mime.trim().parse::<Mime>().ok()?))
+        });
+        let Some((ext, mime)) = parsed else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid mime-map entry `{line}` in {}", path.display()),
+            ));
+        };
+        map.insert(ext, mime);
+    }
+    Ok(map)
+}
+
+// Compiled once at startup, so a malformed schema stops the server rather
+// than failing every metadata request.
+fn read_schema(path: &Path) -> io::Result<jsonschema::Validator> {
+    let text = fs::read_to_string(path)?;
+    let invalid = |e: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid metadata schema {}: {e}", path.display()),
+        )
+    };
+    let schema = serde_json::from_str::<Value>(&text).map_err(|e| invalid(e.to_string()))?;
+    jsonschema::validator_for(&schema).map_err(|e| invalid(e.to_string()))
+}
+
+// Metadata that fails --metadata-schema is refused rather than served.
+fn check_schema(body: &Value, data: &CoreData) -> Result<(), actix_web::Error> {
+    if let Some(schema) = &data.metadata_schema
+        && let Err(e) = schema.validate(body)
+    {
+        return Err(InternalError::new(
+            format!("Package metadata violates the registry schema: {e}"),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .into());
+    }
+    Ok(())
+}
+
+async fn verify_package(
+    meta_path: PathBuf,
+    artifact: PathBuf,
+    data: &web::Data<CoreData>,
+) -> Result<(&'static str, String), actix_web::Error> {
+    let data = data.clone();
+    let Some(meta) = web::block(move || read_metadata(&meta_path, &data)).await? else {
+        return Err(InternalError::new(
+            "Error reading package metadata for verification!",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into());
+    };
+    let (algorithm, expected) = hash::split_hash(&meta.hash);
+    let Some(algorithm) = Algorithm::parse(algorithm) else {
+        return Err(InternalError::new(
+            format!("Unsupported hash algorithm `{algorithm}` in package metadata!"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into());
+    };
+    let digest = web::block(move || algorithm.digest_file(&artifact))
+        .await?
+        .map_err(unavailable)?;
+    if digest.eq_ignore_ascii_case(expected) {
+        Ok((algorithm.header_name(), digest))
+    } else {
+        Err(InternalError::new(
+            "Package failed integrity verification!",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into())
+    }
+}
+
+fn read_metadata(path: &Path, data: &CoreData) -> Option<PackageMetadata> {
+    parse_metadata(path, data).ok()
+}
+
+// With --default-metadata, fields a package leaves out or empty are filled
+// from the defaults before the file has to satisfy PackageMetadata.
+fn parse_metadata(path: &Path, data: &CoreData) -> Result<PackageMetadata, String> {
+    let mut file = fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut text = String::new();
+    file.read_to_string(&mut text).map_err(|e| e.to_string())?;
+    let json = path.extension().is_some_and(|x| x == "json");
+    let Some(defaults) = &data.default_metadata else {
+        return if json {
+            serde_json::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            serde_norway::from_str(&text).map_err(|e| e.to_string())
+        };
+    };
+    let mut value: Value = if json {
+        serde_json::from_str(&text).map_err(|e| e.to_string())?
+    } else {
+        serde_norway::from_str(&text).map_err(|e| e.to_string())?
+    };
+    if let Value::Object(map) = &mut value {
+        for (key, default) in defaults.iter() {
+            let entry = map.entry(key.clone()).or_insert(Value::Null);
+            let empty = match entry {
+                Value::Null => true,
+                Value::String(x) => x.is_empty(),
+                Value::Array(x) => x.is_empty(),
+                _ => false,
+            };
+            if empty {
+                *entry = default.clone();
+            }
+        }
+    }
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+fn read_default_metadata(path: &Path) -> io::Result<serde_json::Map<String, Value>> {
+    let text = fs::read_to_string(path)?;
+    match serde_norway::from_str::<Value>(&text) {
+        Ok(Value::Object(map)) => Ok(map),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid default metadata in {}", path.display()),
+        )),
+    }
+}
+
+// Re-parses a metadata file that failed to load to tell an incomplete file
+// (the author's mistake, 422) from an unreadable or malformed one. Both serde
+// backends word this error as "missing field `name`".
+fn missing_metadata_field(path: &Path, data: &CoreData) -> Option<String> {
+    let err = parse_metadata(path, data).err()?;
+    let rest = &err[err.find("missing field `")? + "missing field `".len()..];
+    Some(rest[..rest.find('`')?].to_string())
+}
+
+fn metadata_to_json(path: &Path, data: &CoreData) -> Option<Value> {
+    serde_json::to_value(read_metadata(path, data)?).ok()
+}
+
+fn resolve_dependencies(body: &mut Value, headers: &HeaderMap, data: &CoreData) -> io::Result<()> {
+    for key in ["runtime_dependencies", "build_dependencies"] {
+        if let Some(Value::Array(deps)) = body.get_mut(key) {
+            for dep in deps.iter_mut() {
+                if let Some(requested) = dep.as_str() {
+                    *dep = resolve_dependency(requested, headers, data)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Splits each dependency string into its name and version constraint. The
+// constraint is checked with VersionReq; entries that fail keep their parts
+// but gain an `error`, so one bad entry doesn't hide the rest.
+fn structure_dependencies(body: &mut Value) {
+    for key in ["runtime_dependencies", "build_dependencies"] {
+        if let Some(Value::Array(deps)) = body.get_mut(key) {
+            for dep in deps.iter_mut() {
+                if let Some(dep_str) = dep.as_str() {
+                    *dep = structure_dependency(dep_str);
+                }
+            }
+        }
+    }
+}
+
+fn structure_dependency(dep: &str) -> Value {
+    let (name, constraint) = split_dependency(dep);
+    let constraint = Some(constraint).filter(|x| !x.is_empty());
+    let error = if name.is_empty() {
+        Some("missing package name".to_string())
+    } else {
+        constraint.and_then(|x| VersionReq::parse(x).err().map(|e| e.to_string()))
+    };
+    match error {
+        Some(error) => json!({ "name": name, "constraint": constraint, "error": error }),
+        None => json!({ "name": name, "constraint": constraint }),
+    }
+}
+
+// Dependencies the requester can't see, through the access lists or an ACL,
+// stay unresolved.
+fn resolve_dependency(dep: &str, headers: &HeaderMap, data: &CoreData) -> io::Result<Value> {
+    let (name, requested) = split_dependency(dep);
+    let resolved = match package_name(name, data).zip(path_check(name, &data.directory)) {
+        Some((canonical, location))
+            if data.access.permits(&canonical) && acl_permits(headers, &location, data)? =>
+        {
+            let requested = Some(requested).filter(|x| !x.is_empty());
+            resolve(&location, requested, Select::Highest, data)?.and_then(|x| resolved_version(&x))
+        }
+        _ => None,
+    };
+    Ok(json!({ "name": name, "requested": requested, "resolved": resolved }))
+}
+
+#[get("/packages/list")]
+async fn list(
+    data: web::Data<CoreData>,
+    info: web::Query<ListQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    index_ready(&data)?;
+    let (since, sort, order) = (info.since, info.sort, info.order);
+    match web::block(move || list_versions(&data, since, sort, order)).await? {
+        // The list is not paginated yet, so the total is simply its length;
+        // it already reflects the ?since filter.
+        Ok((entries, truncated)) => match serde_json::to_string(&entries) {
+            Ok(body) => {
+                let mut res = HttpResponse::Ok();
+                res.insert_header((X_TOTAL_COUNT, entries.len()));
+                if truncated {
+                    res.insert_header(("X-Truncated", "true"));
+                }
+                Ok(res.body(body))
+            }
+            Err(_) => Err(InternalError::new(
+                "Error serializing package list!",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into()),
+        },
+        Err(e) => Err(unavailable(e)),
+    }
+}
+
+// Also reports whether --walk-timeout cut the directory walk short. A
+// truncated list is complete for every package it names, but packages not
+// reached in time (in no particular order) are missing from it entirely.
+fn list_versions(
+    data: &CoreData,
+    since: Option<u64>,
+    sort: ListSort,
+    order: Order,
+) -> io::Result<(Vec<ListEntry>, bool)> {
+    let mut entries = Vec::new();
+    if let Some(index) = &data.registry_index {
+        for (name, versions) in index.packages(data)? {
+            if !data.access.permits(&name) {
+                continue;
+            }
+            for ver in versions {
+                if since.is_none_or(|since| ver.modified > since) {
+                    let entry = ListEntry {
+                        name: name.clone(),
+                        version: ver.version,
+                    };
+                    entries.push((entry, ver.modified));
+                }
+            }
+        }
+        return Ok((sort_entries(entries, data, sort, order), false));
+    }
+    let deadline = data.walk_timeout.map(|x| Instant::now() + x);
+    let mut truncated = false;
+    for pkg in data.directory.read_dir()? {
+        if deadline.is_some_and(|x| Instant::now() >= x) {
+            truncated = true;
+            break;
+        }
+        let pkg = pkg?;
+        if !pkg.path().is_dir() {
+            continue;
+        }
+        let Ok(name) = pkg.file_name().into_string() else {
+            continue;
+        };
+        if !data.access.permits(&name) {
+            continue;
+        }
+        let versions = version_dirs(&pkg.path(), data)?.into_iter().filter(|x| {
+            metadata_file(&x.path(), data)
+                .is_some_and(|x| since.is_none_or(|since| modified_since(&x, since)))
+        });
+        for ver in versions {
+            // Only ?sort=modified needs the extra stat per version.
+            let modified = match sort {
+                ListSort::Modified => metadata_file(&ver.path(), data)
+                    .and_then(|x| modified_secs(&x))
+                    .unwrap_or_default(),
+                _ => 0,
+            };
+            let entry = ListEntry {
+                name: name.clone(),
+                version: ver.file_name().to_string_lossy().into_owned(),
+            };
+            entries.push((entry, modified));
+        }
+    }
+    Ok((sort_entries(entries, data, sort, order), truncated))
+}
+
+// Ties on version or modification time fall back to name ascending whatever
+// the order, so pages stay stable between requests.
+fn sort_entries(
+    mut entries: Vec<(ListEntry, u64)>,
+    data: &CoreData,
+    sort: ListSort,
+    order: Order,
+) -> Vec<ListEntry> {
+    let directed = |x: std::cmp::Ordering| match order {
+        Order::Asc => x,
+        Order::Desc => x.reverse(),
+    };
+    let key = |x: &ListEntry| version_key(strip_v_prefix(&x.version, data));
+    let by_name =
+        |a: &ListEntry, b: &ListEntry| a.name.cmp(&b.name).then_with(|| key(a).cmp(&key(b)));
+    entries.sort_by(|(a, a_modified), (b, b_modified)| match sort {
+        ListSort::Name => directed(by_name(a, b)),
+        ListSort::Version => directed(key(a).cmp(&key(b))).then_with(|| by_name(a, b)),
+        ListSort::Modified => directed(a_modified.cmp(b_modified)).then_with(|| by_name(a, b)),
+    });
+    entries.into_iter().map(|(x, _)| x).collect()
+}
+
+fn modified_since(path: &Path, since: u64) -> bool {
+    // Compared at whole-second resolution, and only as precise as the
+    // filesystem's mtime (FAT has 2s granularity, some network mounts cache
+    // attributes). Clients should poll with a small overlap and dedupe.
+    modified_secs(path).is_some_and(|x| x > since)
+}
+
+fn modified_secs(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+}
+
+#[get("/packages/dependents/{name}")]
+async fn dependents(
+    name: web::Path<String>,
+    data: web::Data<CoreData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Some(name) = package_name(&name, &data) else {
+        return Err(InternalError::new(
+            "You do not have access to this location.",
+            StatusCode::FORBIDDEN,
+        )
+        .into());
+    };
+    if let Some(location) = path_check(&name, &data.directory) {
+        if !data.access.permits(&name) || !is_dir(&location).map_err(unavailable)? {
+            return Err(InternalError::new(
+                "Requested package could not be found.",
+                StatusCode::NOT_FOUND,
+            )
+            .into());
+        }
+    } else {
+        return Err(InternalError::new(
+            "You do not have access to this location.",
+            StatusCode::FORBIDDEN,
+        )
+        .into());
+    }
+    let names = web::block(move || {
+        let mut index = data.dependents.lock().unwrap();
+        index.refresh(&data).map(|_| {
+            let mut names = index.get(&name);
+            names.retain(|x| data.access.permits(x));
+            names
+        })
+    })
+    .await?
+    .map_err(unavailable)?;
+    match serde_json::to_string(&names) {
+        Ok(body) => Ok(HttpResponse::with_body(StatusCode::OK, BoxBody::new(body))),
+        Err(_) => Err(InternalError::new(
+            "Error serializing dependents!",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into()),
+    }
+}
+
+fn split_dependency(dep: &str) -> (&str, &str) {
+    let dep = dep.trim();
+    let end = dep
+        .find(|x: char| x.is_whitespace() || "<>=^~!*,".contains(x))
+        .unwrap_or(dep.len());
+    (&dep[..end], dep[end..].trim())
+}
+
+#[get("/packages/count")]
+async fn count(data: web::Data<CoreData>) -> Result<HttpResponse, actix_web::Error> {
+    index_ready(&data)?;
+    let counts = web::block(move || {
+        let mut cached = data.count_cache.lock().unwrap();
+        if let Some((at, counts)) = *cached
+            && at.elapsed() < data.count_cache_ttl
+        {
+            return Ok(counts);
+        }
+        let counts = count_packages(&data)?;
+        *cached = Some((Instant::now(), counts));
+        Ok(counts)
+    })
+    .await?
+    .map_err(unavailable)?;
+    match serde_json::to_string(&counts) {
+        Ok(body) => Ok(HttpResponse::with_body(StatusCode::OK, BoxBody::new(body))),
+        Err(_) => Err(InternalError::new(
+            "Error serializing package counts!",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .into()),
+    }
+}
+
+fn count_packages(data: &CoreData) -> io::Result<Counts> {
+    let mut counts = Counts::default();
+    if let Some(index) = &data.registry_index {
+        for (name, versions) in index.packages(data)? {
+            if data.access.permits(&name) {
+                counts.packages += 1;
+                counts.versions += versions.len();
+            }
+        }
+        return Ok(counts);
+    }
+    for pkg in data.directory.read_dir()? {
+        let pkg = pkg?;
+        if !pkg.path().is_dir() || !data.access.permits(&pkg.file_name().to_string_lossy()) {
+            continue;
+        }
+        counts.packages += 1;
+        counts.versions += version_dirs(&pkg.path(), data)?
+            .iter()
+            .filter(|x| {
+                metadata_file(&x.path(), data).is_some_and(|x| read_metadata(&x, data).is_some())
+            })
+            .count();
+    }
+    Ok(counts)
+}
+
+#[get("/version")]
+async fn version() -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::with_body(
+        StatusCode::OK,
+        BoxBody::new(env!("CARGO_PKG_VERSION")),
+    ))
+}
+
+// What an adaptive client can rely on here. Anything a flag can turn on or
+// off is read from the config, so the answer describes this instance rather
+// than the build; only routes every instance serves are reported as constants.
+#[get("/capabilities")]
+async fn capabilities(data: web::Data<CoreData>) -> HttpResponse {
+    let body = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "ranges": true,
+        "channels": true,
+        "bundles": true,
+        "lock": true,
+        "dependents": true,
+        "all_matching": true,
+        "directory_listing": !data.disable_directory_listing,
+        "hash_verification": data.verify_hash,
+        "upload": false,
+        "proxy": false,
+        "compression": {
+            "metadata": data.gzip_cache.is_some(),
+            "archives": true,
+        },
+        "cas": data.cas_dir.is_some(),
+        "acls": data.enable_acls,
+        "index": data.registry_index.is_some(),
+        "redirect_latest": data.redirect_latest,
+        "api_versions": [1, 2],
+        "api_default": if data.api_v2 { 2 } else { 1 },
+        "metadata_schema": data.metadata_schema.is_some(),
+        "strip_v_prefix": data.strip_v_prefix,
+        "ci_prerelease": data.ci_prerelease,
+        "gc": data.admin_token.is_some() && !data.read_only,
+        "shutdown": data.shutdown.is_some() && data.admin_token.is_some() && !data.read_only,
+        "read_only": data.read_only,
+        "motd": data.banner.file().is_some(),
+    });
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string())
+}
+
+// Browsers ask for this unprompted; answering 204 when no icon is configured
+// keeps their requests out of the 404s in the access log.
+#[get("/favicon.ico")]
+async fn favicon_ico(data: web::Data<CoreData>) -> HttpResponse {
+    match &data.favicon {
+        Some((mime, icon)) => HttpResponse::Ok()
+            .content_type(mime.clone())
+            .insert_header((header::CACHE_CONTROL, "public, max-age=86400"))
+            .body(icon.clone()),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+#[get("/motd")]
+async fn motd(data: web::Data<CoreData>) -> HttpResponse {
+    let Some(text) = data.banner.text() else {
+        return HttpResponse::NoContent().finish();
+    };
+    let markdown = data
+        .banner
+        .file()
+        .is_some_and(|x| x.extension().is_some_and(|x| x == "md"));
+    let content_type = if markdown {
+        "text/markdown; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    HttpResponse::Ok().content_type(content_type).body(text)
+}
+
+#[derive(Clone)]
+struct CoreData {
+    directory: PathBuf,
+    port: u16,
+    package_cache_control: String,
+    metadata_cache_ttl: u64,
+    version_cache_ttl: u64,
+    stale_while_revalidate: Option<u64>,
+    stale_if_error: Option<u64>,
+    redirect_latest: bool,
+    verify_hash: bool,
+    dependents: Arc<Mutex<DependentsIndex>>,
+    prefer_json: bool,
+    request_timeout: Option<Duration>,
+    download_timeout: Option<Duration>,
+    admin_token: Option<String>,
+    gc_keep: usize,
+    access: Arc<AccessLists>,
+    legal: Arc<LegalBlocks>,
+    banner: Arc<Banner>,
+    version_index: Option<Arc<VersionIndex>>,
+    resolve_cache: Option<Arc<ResolveCache>>,
+    negative_cache: Option<Arc<NegativeCache>>,
+    gzip_cache: Option<Arc<GzipCache>>,
+    webhook_url: Option<String>,
+    artifact_mime: Mime,
+    mime_map: Arc<HashMap<String, Mime>>,
+    count_cache: Arc<Mutex<Option<(Instant, Counts)>>>,
+    count_cache_ttl: Duration,
+    enable_acls: bool,
+    api_v2: bool,
+    downloads: Option<Arc<DownloadGate>>,
+    inflight: Option<Arc<InflightGate>>,
+    metadata_schema: Option<Arc<jsonschema::Validator>>,
+    shutdown: Option<Arc<Notify>>,
+    strip_v_prefix: bool,
+    ci_prerelease: bool,
+    log_ignore: Arc<Vec<LogIgnore>>,
+    cas_dir: Option<PathBuf>,
+    walk_timeout: Option<Duration>,
+    read_only: bool,
+    default_metadata: Option<Arc<serde_json::Map<String, Value>>>,
+    artifact_pattern: String,
+    follow_latest_symlink: bool,
+    pick_first_pax: bool,
+    latest_by: LatestBy,
+    zero_caret: ZeroCaret,
+    fs_retries: u32,
+    quickhash_window: u64,
+    disable_directory_listing: bool,
+    registry_index: Option<Arc<RegistryIndex>>,
+    log_requests_body: bool,
+    extra_headers: Arc<Vec<(header::HeaderName, header::HeaderValue)>>,
+    favicon: Option<(Mime, web::Bytes)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ZeroCaret {
+    Standard,
+    Strict,
+    Loose,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LatestBy {
+    Semver,
+    Mtime,
+}
+
+// Which of several versions matching a range or partial version is served.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Select {
+    #[default]
+    Highest,
+    Lowest,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    v: Option<String>,
+    version: Option<String>,
+    resolve_deps: Option<bool>,
+    with_size: Option<bool>,
+    structured_deps: Option<bool>,
+    all_matching: Option<bool>,
+    limit: Option<usize>,
+    fields: Option<String>,
+    select: Option<Select>,
+}
+
+impl Version {
+    // Some clients spell it `?version=`; `?v=` wins when both are given.
+    fn requested(&self) -> Option<&str> {
+        self.v.as_deref().or(self.version.as_deref())
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    since: Option<u64>,
+    #[serde(default)]
+    sort: ListSort,
+    #[serde(default)]
+    order: Order,
+}
+
+// Unknown values fail to deserialize, which rejects the query with a 400.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ListSort {
+    #[default]
+    Name,
+    Version,
+    Modified,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Serialize)]
+struct ListEntry {
+    name: String,
+    version: String,
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+struct Counts {
+    packages: usize,
+    versions: usize,
+}
+
+const EXIT_BIND_FAILED: i32 = 3;
+const EXIT_PID_FILE: i32 = 4;
+const EXIT_INSECURE_PERMS: i32 = 5;
+
+// Anyone on the host could then plant or replace packages. Permission bits
+// only mean this on Unix; elsewhere the check is skipped.
+#[cfg(unix)]
+fn world_writable(path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::metadata(path)?.permissions().mode() & 0o002 != 0)
+}
+
+#[cfg(not(unix))]
+fn world_writable(_path: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(data: CoreData) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+        logging::warn("Unable to listen for SIGHUP, reloading is disabled");
+        return;
+    };
+    while hangup.recv().await.is_some() {
+        match data.access.reload() {
+            Ok(()) => logging::info("Reloaded access lists"),
+            Err(e) => logging::warn(format!("Failed to reload access lists: {e}")),
+        }
+        if let Err(e) = data.legal.reload() {
+            logging::warn(format!("Failed to reload legal blocks: {e}"));
+        }
+        if let Err(e) = data.banner.reload() {
+            logging::warn(format!("Failed to reload banner: {e}"));
+        }
+        if data.registry_index.is_some() {
+            let data = data.clone();
+            let rebuilt = web::block(move || match &data.registry_index {
+                Some(index) => index.rebuild(&data),
+                None => Ok(()),
+            })
+            .await;
+            match rebuilt {
+                Ok(Ok(())) => logging::info("Rebuilt registry index"),
+                Ok(Err(e)) => logging::warn(format!("Failed to rebuild registry index: {e}")),
+                Err(e) => logging::warn(format!("Failed to rebuild registry index: {e}")),
+            }
+        }
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let mut directory = std::env::current_dir()?;
+    let mut port = 8080u16;
+    let mut package_cache_control = String::from("public, max-age=31536000, immutable");
+    let mut metadata_cache_ttl = 60u64;
+    let mut version_cache_ttl = 3600u64;
+    let mut stale_while_revalidate = None;
+    let mut stale_if_error = None;
+    let mut redirect_latest = false;
+    let mut verify_hash = false;
+    let mut prefer_json = false;
+    let mut request_timeout = None;
+    let mut download_timeout = None;
+    let mut admin_token = None;
+    let mut gc_keep = 5usize;
+    let mut allow_list = None;
+    let mut deny_list = None;
+    let mut version_index = None;
+    let mut resolve_cache = None;
+    let mut negative_cache = None;
+    let mut gzip_cache = None;
+    let mut webhook_url = None;
+    let mut latest_by = LatestBy::Semver;
+    let mut zero_caret = ZeroCaret::Standard;
+    let mut fs_retries = 0u32;
+    let mut quickhash_kb = 64u64;
+    let mut disable_directory_listing = false;
+    let mut keep_alive = None;
+    let mut index_file = None;
+    let mut rebuild_index = false;
+    let mut log_requests_body = false;
+    let mut legal_block = None;
+    let mut extra_headers = Vec::new();
+    let mut pid_file = None;
+    let mut favicon = None;
+    let mut log_format = LogFormat::Text;
+    let mut artifact_mime = "application/vnd.pax+octet-stream".parse::<Mime>().unwrap();
+    let mut mime_map = HashMap::new();
+    let mut count_cache_ttl = Duration::from_secs(10);
+    let mut enable_acls = false;
+    let mut api_v2 = false;
+    let mut max_downloads = None;
+    let mut max_inflight = None;
+    let mut metadata_schema = None;
+    let mut shutdown = None;
+    let mut strip_v_prefix = false;
+    let mut ci_prerelease = false;
+    let mut log_ignore = Vec::new();
+    let mut cas_dir = None;
+    let mut walk_timeout = None;
+    let mut read_only = false;
+    let mut strict_perms = false;
+    let mut default_metadata = None;
+    let mut artifact_pattern = String::from("{name}-{ver}.pax");
+    let mut banner = None;
+    let mut download_queue_len = 64usize;
+    let mut follow_latest_symlink = false;
+    let mut pick_first_pax = false;
+    let mut strip_prefix = None;
+    let mut trailing_slash = TrailingSlash::Trim;
+    let args = std::env::args().collect::<Vec<String>>();
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(arg) = arg.strip_prefix("--") {
+            match arg {
+                "directory" => {
+                    if let Some(loc) = args.next() {
+                        directory = PathBuf::from(loc)
+                    }
+                }
+                "port" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u16>()) {
+                        port = val
+                    }
+                }
+                "package-cache-control" => {
+                    if let Some(val) = args.next() {
+                        package_cache_control = val.clone()
+                    }
+                }
+                "metadata-cache-ttl" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        metadata_cache_ttl = val
+                    }
+                }
+                "version-cache-ttl" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        version_cache_ttl = val
+                    }
+                }
+                "stale-while-revalidate" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        stale_while_revalidate = Some(val)
+                    }
+                }
+                "stale-if-error" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        stale_if_error = Some(val)
+                    }
+                }
+                "redirect-latest" => redirect_latest = true,
+                "verify-hash" => verify_hash = true,
+                "prefer-json-metadata" => prefer_json = true,
+                "request-timeout" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        request_timeout = Some(Duration::from_secs(val))
+                    }
+                }
+                "walk-timeout" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        walk_timeout = Some(Duration::from_secs(val))
+                    }
+                }
+                "download-timeout" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        download_timeout = Some(Duration::from_secs(val))
+                    }
+                }
+                "pid-file" => {
+                    if let Some(loc) = args.next() {
+                        pid_file = Some(PathBuf::from(loc))
+                    }
+                }
+                "banner" => {
+                    if let Some(loc) = args.next() {
+                        banner = Some(PathBuf::from(loc))
+                    }
+                }
+                "favicon" => {
+                    if let Some(loc) = args.next() {
+                        let path = Path::new(loc);
+                        let ext = path.extension().and_then(|x| x.to_str()).unwrap_or("ico");
+                        let mime = match ext {
+                            "ico" => "image/x-icon".parse().unwrap(),
+                            ext => actix_files::file_extension_to_mime(ext),
+                        };
+                        favicon = Some((mime, web::Bytes::from(fs::read(path)?)))
+                    }
+                }
+                "header" => {
+                    if let Some(val) = args.next() {
+                        match parse_header(val) {
+                            Some(header) => extra_headers.push(header),
+                            None => panic!("Invalid header {val}!"),
+                        }
+                    }
+                }
+                "admin-token" => {
+                    if let Some(val) = args.next() {
+                        admin_token = Some(val.clone())
+                    }
+                }
+                "gc-keep" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<usize>()) {
+                        gc_keep = val
+                    }
+                }
+                "cas-dir" => {
+                    if let Some(loc) = args.next() {
+                        cas_dir = Some(PathBuf::from(loc))
+                    }
+                }
+                "allow-list" => {
+                    if let Some(loc) = args.next() {
+                        allow_list = Some(PathBuf::from(loc))
+                    }
+                }
+                "version-index" => version_index = Some(Arc::default()),
+                "gzip-metadata" => gzip_cache = Some(Arc::default()),
+                "negative-cache-ttl" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        negative_cache =
+                            Some(Arc::new(NegativeCache::new(Duration::from_secs(val))))
+                    }
+                }
+                "resolve-cache-ttl" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        resolve_cache = Some(Arc::new(ResolveCache::new(Duration::from_secs(val))))
+                    }
+                }
+                "artifact-pattern" => {
+                    if let Some(val) = args.next() {
+                        artifact_pattern = val.clone()
+                    }
+                }
+                "artifact-mime" => {
+                    if let Some(val) = args.next() {
+                        artifact_mime = val
+                            .parse()
+                            .unwrap_or_else(|_| panic!("Invalid MIME type {val}!"))
+                    }
+                }
+                "mime-map" => {
+                    if let Some(loc) = args.next() {
+                        mime_map = read_mime_map(Path::new(loc))?
                     }
-                };
-            }
-        } else {
-            return Err(InternalError::new(
-                "Requested file could not be found.",
-                StatusCode::NOT_FOUND,
-            )
-            .into());
-        }
-    } else {
-        return Err(InternalError::new(
-            "You do not have access to this location.",
-            StatusCode::FORBIDDEN,
-        )
-        .into());
-    }
-    Err(InternalError::new("Something went wrong.", StatusCode::INTERNAL_SERVER_ERROR).into())
-}
-
-fn yaml_file_to_json_str(path: &PathBuf) -> Option<String> {
-    let mut file = fs::File::open(path).ok()?;
-    let mut data = String::new();
-    file.read_to_string(&mut data).ok()?;
-    let body: PackageMetadata = serde_norway::from_str(&data).ok()?;
-    serde_json::to_string(&body).ok()
-}
-
-#[get("/version")]
-async fn version() -> Result<HttpResponse, actix_web::Error> {
-    Ok(HttpResponse::with_body(
-        StatusCode::OK,
-        BoxBody::new(env!("CARGO_PKG_VERSION")),
-    ))
-}
-
-#[derive(Clone)]
-struct CoreData {
-    directory: PathBuf,
-}
-
-#[derive(Deserialize)]
-struct Version {
-    v: Option<String>,
-}
-
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let mut directory = std::env::current_dir()?;
-    let mut port = 8080u16;
-    let args = std::env::args().collect::<Vec<String>>();
-    let mut args = args.iter().skip(1);
-    while let Some(arg) = args.next() {
-        if let Some(arg) = arg.strip_prefix("--") {
-            match arg {
-                "directory" => {
+                }
+                "default-metadata" => {
                     if let Some(loc) = args.next() {
-                        directory = PathBuf::from(loc)
+                        default_metadata = Some(Arc::new(read_default_metadata(Path::new(loc))?))
                     }
                 }
-                "port" => {
-                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u16>()) {
-                        port = val
+                "metadata-schema" => {
+                    if let Some(loc) = args.next() {
+                        metadata_schema = Some(Arc::new(read_schema(Path::new(loc))?))
+                    }
+                }
+                "count-cache-ttl" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        count_cache_ttl = Duration::from_secs(val)
+                    }
+                }
+                "enable-acls" => enable_acls = true,
+                "api-v2" => api_v2 = true,
+                "enable-shutdown-endpoint" => shutdown = Some(Arc::default()),
+                "read-only" => read_only = true,
+                "strict-perms" => strict_perms = true,
+                "follow-latest-symlink" => follow_latest_symlink = true,
+                "pick-first-pax" => pick_first_pax = true,
+                "strip-v-prefix" => strip_v_prefix = true,
+                "ci-prerelease" => ci_prerelease = true,
+                "disable-directory-listing" => disable_directory_listing = true,
+                "strip-prefix" => strip_prefix = args.next(),
+                "quickhash-kb" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        quickhash_kb = val
+                    }
+                }
+                "index-file" => {
+                    if let Some(loc) = args.next() {
+                        index_file = Some(PathBuf::from(loc))
+                    }
+                }
+                "rebuild-index" => rebuild_index = true,
+                "legal-block" => {
+                    if let Some(loc) = args.next() {
+                        legal_block = Some(PathBuf::from(loc))
+                    }
+                }
+                "log-ignore" => {
+                    if let Some(val) = args.next() {
+                        match LogIgnore::parse(val) {
+                            Some(pattern) => log_ignore.push(pattern),
+                            None => panic!("Invalid log-ignore pattern {val}!"),
+                        }
+                    }
+                }
+                // Debug only: logs headers and a body summary for every request.
+                "log-requests-body" => log_requests_body = true,
+                "keep-alive" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u64>()) {
+                        keep_alive = Some(val)
+                    }
+                }
+                "fs-retries" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<u32>()) {
+                        fs_retries = val
+                    }
+                }
+                "log-format" => match args.next().map(String::as_str) {
+                    Some("text") => log_format = LogFormat::Text,
+                    Some("json") => log_format = LogFormat::Json,
+                    other => panic!("Unknown log format {}!", other.unwrap_or_default()),
+                },
+                // Duplicate slashes are merged either way; `trim` also drops a
+                // trailing one so `/packages/metadata/foo/` finds `foo`.
+                "trailing-slash" => match args.next().map(String::as_str) {
+                    Some("trim") => trailing_slash = TrailingSlash::Trim,
+                    Some("merge") => trailing_slash = TrailingSlash::MergeOnly,
+                    other => panic!("Unknown trailing-slash mode {}!", other.unwrap_or_default()),
+                },
+                "latest-by" => match args.next().map(String::as_str) {
+                    Some("semver") => latest_by = LatestBy::Semver,
+                    Some("mtime") => latest_by = LatestBy::Mtime,
+                    other => panic!("Unknown latest-by mode {}!", other.unwrap_or_default()),
+                },
+                "zero-caret" => match args.next().map(String::as_str) {
+                    Some("standard") => zero_caret = ZeroCaret::Standard,
+                    Some("strict") => zero_caret = ZeroCaret::Strict,
+                    Some("loose") => zero_caret = ZeroCaret::Loose,
+                    other => panic!("Unknown zero-caret mode {}!", other.unwrap_or_default()),
+                },
+                "webhook-url" => {
+                    if let Some(url) = args.next() {
+                        // No TLS support is built in, so refuse https:// up
+                        // front rather than failing every delivery.
+                        if !url.starts_with("http://") {
+                            panic!("Webhook URL must start with http://!");
+                        }
+                        webhook_url = Some(url.to_string())
+                    }
+                }
+                "max-concurrent-downloads" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<usize>()) {
+                        max_downloads = Some(val)
+                    }
+                }
+                "max-inflight" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<usize>()) {
+                        max_inflight = Some(val)
+                    }
+                }
+                "download-queue-len" => {
+                    if let Some(Ok(val)) = args.next().map(|x| x.parse::<usize>()) {
+                        download_queue_len = val
+                    }
+                }
+                "deny-list" => {
+                    if let Some(loc) = args.next() {
+                        deny_list = Some(PathBuf::from(loc))
                     }
                 }
                 _ => panic!("Unknown long-flag {arg}!"),
@@ -219,19 +2809,278 @@ async fn main() -> std::io::Result<()> {
             panic!("Unknown parameter {arg}!");
         }
     }
-    println!("Using folder {}", directory.display());
-    println!("Using port {port}");
-    let data = CoreData { directory };
-    HttpServer::new(move || {
+    // Packages live in a subdirectory of the root; every lookup (and
+    // path_check's enforcement) is then relative to that subdirectory.
+    if let Some(prefix) = strip_prefix {
+        match path_check(prefix, &directory) {
+            Some(loc) => directory = loc,
+            None => panic!("Invalid strip-prefix {prefix}!"),
+        }
+    }
+    logging::init(log_format);
+    logging::info(format!("Using folder {}", directory.display()));
+    logging::info(format!("Using port {port}"));
+    if world_writable(&directory)? {
+        let message = format!("Package folder {} is world-writable", directory.display());
+        if strict_perms {
+            logging::error(format!("{message}, refusing to start under --strict-perms"));
+            std::process::exit(EXIT_INSECURE_PERMS);
+        }
+        logging::warn(message);
+    }
+    let data = CoreData {
+        directory,
+        port,
+        package_cache_control,
+        metadata_cache_ttl,
+        version_cache_ttl,
+        stale_while_revalidate,
+        stale_if_error,
+        redirect_latest,
+        verify_hash,
+        dependents: Arc::default(),
+        prefer_json,
+        request_timeout,
+        download_timeout,
+        admin_token,
+        gc_keep,
+        access: Arc::new(AccessLists::load(allow_list, deny_list)?),
+        legal: Arc::new(LegalBlocks::load(legal_block)?),
+        version_index,
+        resolve_cache,
+        negative_cache,
+        gzip_cache,
+        webhook_url,
+        latest_by,
+        zero_caret,
+        fs_retries,
+        quickhash_window: quickhash_kb.saturating_mul(1024),
+        disable_directory_listing,
+        registry_index: index_file.map(|x| Arc::new(RegistryIndex::new(x))),
+        log_requests_body,
+        extra_headers: Arc::new(extra_headers),
+        favicon,
+        artifact_mime,
+        mime_map: Arc::new(mime_map),
+        count_cache: Arc::default(),
+        count_cache_ttl,
+        enable_acls,
+        api_v2,
+        downloads: max_downloads.map(|x| Arc::new(DownloadGate::new(x, download_queue_len))),
+        inflight: max_inflight.map(|x| Arc::new(InflightGate::new(x))),
+        metadata_schema,
+        shutdown: shutdown.clone(),
+        strip_v_prefix,
+        ci_prerelease,
+        // Any --log-ignore replaces the default probe paths entirely.
+        log_ignore: Arc::new(if log_ignore.is_empty() {
+            LogIgnore::defaults()
+        } else {
+            log_ignore
+        }),
+        cas_dir,
+        walk_timeout,
+        read_only,
+        default_metadata,
+        artifact_pattern,
+        banner: Arc::new(Banner::load(banner)?),
+        follow_latest_symlink,
+        pick_first_pax,
+    };
+    match &data.registry_index {
+        Some(index) if rebuild_index => {
+            index.rebuild(&data)?;
+            logging::info(format!("Wrote index to {}", index.file().display()));
+            return Ok(());
+        }
+        Some(index) => index.load_or_build(&data)?,
+        None if rebuild_index => panic!("rebuild-index requires an index-file!"),
+        None => {}
+    }
+    #[cfg(unix)]
+    actix_web::rt::spawn(reload_on_hangup(data.clone()));
+    let shutdown_endpoint = shutdown.is_some();
+    let server = HttpServer::new(move || {
+        let (request_timeout, download_timeout) = (data.request_timeout, data.download_timeout);
+        let api_v2 = data.api_v2;
+        let log_requests_body = data.log_requests_body;
+        let extra_headers = data.extra_headers.clone();
+        let inflight = data.inflight.clone();
+        let log_ignore = data.log_ignore.clone();
         App::new()
             .app_data(web::Data::new(data.clone()))
+            .wrap_fn(move |req, srv| {
+                let path = req.path().to_string();
+                let budget = if path.starts_with("/package/") {
+                    download_timeout
+                } else {
+                    request_timeout
+                };
+                let res = srv.call(req);
+                async move {
+                    let Some(budget) = budget else {
+                        return res.await;
+                    };
+                    match actix_web::rt::time::timeout(budget, res).await {
+                        Ok(res) => res,
+                        Err(_) => {
+                            logging::warn(format!("Request to {path} timed out after {budget:?}"));
+                            Err(InternalError::new(
+                                "Request took too long to process.",
+                                StatusCode::GATEWAY_TIMEOUT,
+                            )
+                            .into())
+                        }
+                    }
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let res = match envelope::wanted(req.headers(), api_v2) {
+                    Ok(wrap) => Ok((wrap, srv.call(req))),
+                    Err(e) => Err(e),
+                };
+                async move {
+                    let (wrap, res) = res?;
+                    let res = res.await;
+                    if wrap {
+                        envelope::envelope(res).await
+                    } else {
+                        res
+                    }
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let res = match inflight.as_ref().map(InflightGate::enter).transpose() {
+                    Ok(guard) => Ok((guard, srv.call(req))),
+                    Err(e) => Err(e),
+                };
+                async move {
+                    let (_inflight, res) = res?;
+                    res.await
+                }
+            })
+            .wrap_fn(move |mut req, srv| {
+                let start = Instant::now();
+                let (method, path) = (req.method().clone(), req.path().to_string());
+                let quiet = log_ignore.iter().any(|x| x.matches(&path));
+                let supplied = req.headers().get("X-Request-Id");
+                let id = logging::request_id(supplied.and_then(|x| x.to_str().ok()));
+                if log_requests_body {
+                    logging::trace_request(&mut req, &id);
+                }
+                let res = srv.call(req);
+                async move {
+                    let mut res = res.await;
+                    let status = match &res {
+                        Ok(res) => res.status(),
+                        Err(e) => e.as_response_error().status_code(),
+                    };
+                    if !quiet {
+                        logging::access(&method, &path, status, start.elapsed(), &id);
+                    }
+                    if let Ok(res) = &mut res
+                        && let Ok(id) = header::HeaderValue::from_str(&id)
+                    {
+                        res.headers_mut().insert(X_REQUEST_ID, id);
+                    }
+                    res
+                }
+            })
+            .wrap(NormalizePath::new(trailing_slash))
+            // Outermost, so errors raised by the other middleware (timeouts,
+            // Accept-Version, --max-inflight) get the operator's headers too.
+            .wrap_fn(move |req, srv| {
+                let res = srv.call(req);
+                let extra_headers = extra_headers.clone();
+                async move {
+                    let add = |headers: &mut HeaderMap| {
+                        for (name, value) in extra_headers.iter() {
+                            headers.insert(name.clone(), value.clone());
+                        }
+                    };
+                    match res.await {
+                        Ok(mut res) => {
+                            add(res.headers_mut());
+                            Ok(res)
+                        }
+                        // The request is gone by now, so the error is rebuilt
+                        // around a response that already carries them.
+                        Err(e) => {
+                            let mut res = e.error_response();
+                            add(res.headers_mut());
+                            Err(InternalError::from_response(e, res).into())
+                        }
+                    }
+                }
+            })
             .service(metadata)
+            .service(metadata_latest)
+            .service(package_tags)
+            .service(metadata_raw)
+            .service(metadata_channels)
+            .service(metadata_version)
+            .service(package_all)
             .service(package)
+            .service(bundle)
+            .service(quickhash)
+            .service(package_file)
+            .service(doc)
+            .service(list)
+            .service(dependents)
+            .service(count)
             .service(version)
-    })
-    .bind(("0.0.0.0", port))?
-    .run()
-    .await
+            .service(capabilities)
+            .service(favicon_ico)
+            .service(motd)
+            .service(lock::lock)
+            .service(admin::gc)
+            .service(admin::config)
+            .configure(|cfg| {
+                if shutdown_endpoint {
+                    cfg.service(admin::shutdown);
+                }
+            })
+    });
+    // Idle connections are closed after this long. Keep it below any idle
+    // timeout on load balancers in front of the server, or they may reuse a
+    // connection the server is about to close; 0 disables keep-alive.
+    let server = match keep_alive {
+        Some(0) => server.keep_alive(KeepAlive::Disabled),
+        Some(secs) => server.keep_alive(Duration::from_secs(secs)),
+        None => server,
+    }
+    .bind(("0.0.0.0", port));
+    match server {
+        Ok(server) => {
+            let _pid_file = match pid_file.map(PidFile::create).transpose() {
+                Ok(pid_file) => pid_file,
+                Err(e) => {
+                    logging::error(format!("Failed to write PID file: {e}"));
+                    std::process::exit(EXIT_PID_FILE);
+                }
+            };
+            let server = server.run();
+            if let Some(shutdown) = shutdown {
+                let handle = server.handle();
+                actix_web::rt::spawn(async move {
+                    shutdown.notified().await;
+                    logging::info("Shutdown requested via /admin/shutdown");
+                    handle.stop(true).await;
+                });
+            }
+            server.await
+        }
+        Err(e) => {
+            let reason = match e.kind() {
+                io::ErrorKind::AddrInUse => String::from("address already in use"),
+                io::ErrorKind::PermissionDenied => String::from("permission denied"),
+                io::ErrorKind::AddrNotAvailable => String::from("address not available"),
+                _ => e.to_string(),
+            };
+            logging::error(format!("Failed to bind 0.0.0.0:{port}: {reason}"));
+            std::process::exit(EXIT_BIND_FAILED);
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -247,6 +3096,10 @@ struct PackageMetadata {
     uninstall: String,
     purge: String,
     hash: String,
+    // Translated descriptions keyed by language tag, chosen from per request
+    // by Accept-Language.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    description_i18n: BTreeMap<String, String>,
 }
 
 fn path_check(subpath_str: &str, origpath: &Path) -> Option<PathBuf> {
@@ -272,6 +3125,7 @@ fn path_check(subpath_str: &str, origpath: &Path) -> Option<PathBuf> {
                 if Path::new(&comp)
                     .components()
                     .all(|c| matches!(c, Component::Normal(_)))
+                    && is_portable_component(&comp.to_string_lossy())
                 {
                     finalpath.push(comp)
                 } else {
@@ -286,3 +3140,844 @@ fn path_check(subpath_str: &str, origpath: &Path) -> Option<PathBuf> {
     }
     Some(finalpath)
 }
+
+fn is_portable_component(comp: &str) -> bool {
+    // Reject anything that another platform (or a later decoding step) could
+    // reinterpret as a separator, drive/UNC prefix or parent directory.
+    // Windows also drops trailing dots and spaces, so `lib.` would open `lib`.
+    !comp.contains(['\\', '\0', ':', '%'])
+        && !comp.ends_with([' ', '.'])
+        && !comp.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{TestRequest, call_service, init_service, read_body};
+    use std::{
+        fs::File,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::SystemTime,
+    };
+
+    // A scratch package directory, removed again when dropped. Shared with the
+    // other modules' tests.
+    pub(crate) struct Registry(pub(crate) PathBuf);
+
+    impl Registry {
+        pub(crate) fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "pax-server-test-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        // A version directory whose metadata names `ver`, returning the
+        // metadata file.
+        pub(crate) fn publish(&self, name: &str, ver: &str) -> PathBuf {
+            self.publish_with(name, ver, &[])
+        }
+
+        pub(crate) fn publish_with(&self, name: &str, ver: &str, deps: &[&str]) -> PathBuf {
+            let dir = self.0.join(name).join(ver);
+            fs::create_dir_all(&dir).unwrap();
+            let meta = dir.join("metadata.yaml");
+            let deps = serde_json::to_string(deps).unwrap();
+            fs::write(
+                &meta,
+                format!(
+                    "name: {name}\ndescription: d\nversion: {ver}\norigin: o\n\
+                     build_dependencies: []\nruntime_dependencies: {deps}\n\
+                     build: b\ninstall: i\nuninstall: u\npurge: p\nhash: h\n"
+                ),
+            )
+            .unwrap();
+            meta
+        }
+
+        // The config main builds when no flags are given.
+        pub(crate) fn data(&self) -> CoreData {
+            CoreData {
+                directory: self.0.clone(),
+                port: 8080,
+                package_cache_control: String::from("public, max-age=31536000, immutable"),
+                metadata_cache_ttl: 60,
+                version_cache_ttl: 3600,
+                stale_while_revalidate: None,
+                stale_if_error: None,
+                redirect_latest: false,
+                verify_hash: false,
+                dependents: Arc::default(),
+                prefer_json: false,
+                request_timeout: None,
+                download_timeout: None,
+                admin_token: None,
+                gc_keep: 5,
+                access: Arc::new(AccessLists::load(None, None).unwrap()),
+                legal: Arc::new(LegalBlocks::load(None).unwrap()),
+                banner: Arc::new(Banner::load(None).unwrap()),
+                version_index: None,
+                resolve_cache: None,
+                negative_cache: None,
+                gzip_cache: None,
+                webhook_url: None,
+                artifact_mime: "application/vnd.pax+octet-stream".parse().unwrap(),
+                mime_map: Arc::default(),
+                count_cache: Arc::default(),
+                count_cache_ttl: Duration::from_secs(10),
+                enable_acls: false,
+                api_v2: false,
+                downloads: None,
+                inflight: None,
+                metadata_schema: None,
+                shutdown: None,
+                strip_v_prefix: false,
+                ci_prerelease: false,
+                log_ignore: Arc::new(LogIgnore::defaults()),
+                cas_dir: None,
+                walk_timeout: None,
+                read_only: false,
+                default_metadata: None,
+                artifact_pattern: String::from("{name}-{ver}.pax"),
+                follow_latest_symlink: false,
+                pick_first_pax: false,
+                latest_by: LatestBy::Semver,
+                zero_caret: ZeroCaret::Standard,
+                fs_retries: 0,
+                quickhash_window: 64 * 1024,
+                disable_directory_listing: false,
+                registry_index: None,
+                log_requests_body: false,
+                extra_headers: Arc::default(),
+                favicon: None,
+            }
+        }
+    }
+
+    impl Drop for Registry {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn set_modified(path: &Path, secs: u64) {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        File::options()
+            .write(true)
+            .open(path)
+            .and_then(|x| x.set_modified(time))
+            .unwrap();
+    }
+
+    fn listed(entries: &[ListEntry]) -> Vec<(&str, &str)> {
+        entries
+            .iter()
+            .map(|x| (x.name.as_str(), x.version.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn list_since_keeps_only_newer_metadata() {
+        let registry = Registry::new();
+        set_modified(&registry.publish("lib", "1.0.0"), 1_000);
+        set_modified(&registry.publish("lib", "2.0.0"), 3_000);
+        let data = registry.data();
+        let since = |since| {
+            list_versions(&data, since, ListSort::Name, Order::Asc)
+                .unwrap()
+                .0
+        };
+        assert_eq!(listed(&since(None)), [("lib", "1.0.0"), ("lib", "2.0.0")]);
+        assert_eq!(listed(&since(Some(2_000))), [("lib", "2.0.0")]);
+        // Strictly newer: a file modified at the timestamp itself is left out.
+        assert_eq!(listed(&since(Some(3_000))), []);
+    }
+
+    // Whatever path_check lets through must be the root plus plain names.
+    fn assert_contained(input: &str, root: &Path) {
+        if let Some(path) = path_check(input, root) {
+            let rest = path
+                .strip_prefix(root)
+                .unwrap_or_else(|_| panic!("{input:?} escaped to {path:?}"));
+            assert!(
+                rest.components().all(|x| matches!(x, Component::Normal(_))),
+                "{input:?} resolved to {path:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn path_check_rejects_tricky_segments() {
+        let root = Path::new("/srv/pax");
+        for input in [
+            "..",
+            "../etc",
+            "a/../../etc",
+            "/..",
+            "..\\..\\etc",
+            "a\\..",
+            "C:\\Windows",
+            "C:",
+            "\\\\server\\share",
+            "//server/share/..",
+            "a\0b",
+            "..\0",
+            "%2e%2e",
+            "%2e%2e%2fetc",
+            "a%2F..",
+            "...",
+            ". .",
+            "a/..  ",
+            "a/.. .",
+            "lib.",
+            "a/\u{1b}",
+        ] {
+            assert_eq!(path_check(input, root), None, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn path_check_accepts_plain_names() {
+        let root = Path::new("/srv/pax");
+        assert_eq!(path_check("lib", root), Some(root.join("lib")));
+        assert_eq!(path_check("/lib/1.0.0", root), Some(root.join("lib/1.0.0")));
+        assert_eq!(path_check("./lib", root), Some(root.join("lib")));
+        assert_eq!(path_check(".hidden", root), Some(root.join(".hidden")));
+        assert_eq!(path_check("", root), Some(root.to_path_buf()));
+    }
+
+    // Every arrangement of up to four pieces, including ones that only turn
+    // into traversal once joined, stays under the root.
+    #[test]
+    fn path_check_never_escapes_root() {
+        let root = Path::new("/srv/pax");
+        let pieces = [
+            "", "a", ".", "..", "/", "\\", "\0", ":", "%2e", "%2f", " ", "C:", "\u{7f}",
+        ];
+        let n = pieces.len();
+        for len in 1..=4u32 {
+            for mut i in 0..n.pow(len) {
+                let mut input = String::new();
+                for _ in 0..len {
+                    input.push_str(pieces[i % n]);
+                    i /= n;
+                }
+                assert_contained(&input, root);
+            }
+        }
+    }
+
+    // A fixed-seed xorshift over a traversal-heavy alphabet, for inputs
+    // longer than the exhaustive search above reaches.
+    #[test]
+    fn path_check_never_escapes_root_random() {
+        let root = Path::new("/srv/pax");
+        let alphabet = ['.', '.', '/', '/', '\\', '\0', ':', '%', 'a', ' ', '~', 'é'];
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..20_000 {
+            let len = next() % 24;
+            let input: String = (0..len)
+                .map(|_| alphabet[next() as usize % alphabet.len()])
+                .collect();
+            assert_contained(&input, root);
+        }
+    }
+
+    // The version directory `ver` resolves to within `name`, if any.
+    fn resolved(data: &CoreData, name: &str, ver: &str, select: Select) -> Option<String> {
+        get_version(&data.directory.join(name), ver, select, data)
+            .unwrap()
+            .and_then(|x| resolved_version(&x))
+    }
+
+    #[test]
+    fn prerelease_prefix_picks_highest_match() {
+        let registry = Registry::new();
+        for ver in [
+            "1.2.0-alpha.9",
+            "1.2.0-beta.1",
+            "1.2.0-beta.3",
+            "1.2.0",
+            "1.3.0-beta.5",
+        ] {
+            registry.publish("lib", ver);
+        }
+        let data = registry.data();
+        let highest = |ver| resolved(&data, "lib", ver, Select::Highest);
+        assert_eq!(highest("1.2.0-beta").as_deref(), Some("1.2.0-beta.3"));
+        assert_eq!(highest("1.2.0-beta.1").as_deref(), Some("1.2.0-beta.1"));
+        // Identifiers are matched whole, so `bet` is not a prefix of `beta`.
+        assert_eq!(highest("1.2.0-bet"), None);
+        assert_eq!(highest("1.2.0-rc"), None);
+    }
+
+    #[test]
+    fn major_only_query_matches_bare_major_directory() {
+        let registry = Registry::new();
+        for ver in ["1.9.0", "2", "20.0.0"] {
+            registry.publish("lib", ver);
+        }
+        let data = registry.data();
+        let find = |ver| resolved(&data, "lib", ver, Select::Highest);
+        assert_eq!(find("2").as_deref(), Some("2"));
+        registry.publish("lib", "2.0.1");
+        // `2` sorts as 2.0.0, below any real 2.x release.
+        assert_eq!(find("2").as_deref(), Some("2.0.1"));
+        assert_eq!(find("1").as_deref(), Some("1.9.0"));
+    }
+
+    #[actix_web::test]
+    async fn metadata_honours_if_none_match() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(metadata),
+        )
+        .await;
+        let get = || TestRequest::get().uri("/packages/metadata/lib?v=1.0.0");
+        let res = call_service(&app, get().to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+        let req = get().insert_header((header::IF_NONE_MATCH, etag.clone()));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::ETAG), Some(&etag));
+        assert!(read_body(res).await.is_empty());
+
+        let req = get().insert_header((header::IF_NONE_MATCH, "W/\"stale\""));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn metadata_sends_vary_and_weak_etag() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(metadata),
+        )
+        .await;
+        let req = TestRequest::get().uri("/packages/metadata/lib");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let vary = res.headers().get(header::VARY).unwrap().to_str().unwrap();
+        assert!(vary.contains("Accept") && vary.contains("Accept-Encoding"));
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        assert!(etag.to_str().unwrap().starts_with("W/"));
+
+        let req = TestRequest::get()
+            .uri("/packages/metadata/lib")
+            .insert_header((header::IF_NONE_MATCH, etag));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(res.headers().contains_key(header::VARY));
+    }
+
+    // The same bytes stored as YAML and as JSON are different representations.
+    #[test]
+    fn metadata_etag_differs_by_source_format() {
+        let registry = Registry::new();
+        let yaml = registry.publish("lib", "1.0.0");
+        let json = yaml.with_file_name("metadata.json");
+        fs::copy(&yaml, &json).unwrap();
+        set_modified(&yaml, 1_000);
+        set_modified(&json, 1_000);
+        assert_ne!(metadata_etag(&yaml).unwrap(), metadata_etag(&json).unwrap());
+    }
+
+    #[actix_web::test]
+    async fn package_rejects_unsatisfiable_range() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0");
+        fs::write(registry.0.join("lib/lib-1.0.0.pax"), [0u8; 64]).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(package),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/package/lib/1.0.0")
+            .insert_header((header::RANGE, "bytes=999999999-"));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            res.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes */64"
+        );
+
+        let req = TestRequest::get()
+            .uri("/package/lib/1.0.0")
+            .insert_header((header::RANGE, "bytes=60-"));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(read_body(res).await.len(), 4);
+    }
+
+    #[actix_web::test]
+    async fn encoded_names_are_decoded_once_and_checked() {
+        let registry = Registry::new();
+        registry.publish("my lib", "1.0.0");
+        fs::write(registry.0.join("secret.yaml"), "hash: h\n").unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(metadata),
+        )
+        .await;
+        let status = |uri: &str| {
+            let req = TestRequest::get().uri(uri).to_request();
+            async { call_service(&app, req).await.status() }
+        };
+        assert_eq!(status("/packages/metadata/my%20lib").await, StatusCode::OK);
+        for uri in [
+            "/packages/metadata/%2E%2E",
+            "/packages/metadata/%2e%2e%2Fetc",
+            "/packages/metadata/..%2F..%2Fetc",
+            "/packages/metadata/my%20lib%2F..%2F..",
+            "/packages/metadata/%2E%2E%5C%2E%2E",
+            "/packages/metadata/%252E%252E",
+            "/packages/metadata/secret.yaml%00",
+        ] {
+            let status = status(uri).await;
+            assert!(status.is_client_error(), "{uri} gave {status}");
+        }
+    }
+
+    #[test]
+    fn channels_resolve_through_their_query() {
+        let registry = Registry::new();
+        for ver in ["1.0.0", "1.4.0", "2.0.0-beta.1", "stable"] {
+            registry.publish("lib", ver);
+        }
+        fs::write(
+            registry.0.join("lib/channels.yaml"),
+            "stable: ^1\nbeta: \">=2.0.0-0\"\n",
+        )
+        .unwrap();
+        let data = registry.data();
+        let find = |ver| resolved(&data, "lib", ver, Select::Highest);
+        // The channel wins over a directory that happens to share its name.
+        assert_eq!(find("stable").as_deref(), Some("1.4.0"));
+        assert_eq!(find("beta").as_deref(), Some("2.0.0-beta.1"));
+        // Names the file doesn't define are ordinary version queries.
+        assert_eq!(find("1").as_deref(), Some("1.4.0"));
+        assert_eq!(find("nightly"), None);
+    }
+
+    #[actix_web::test]
+    async fn metadata_missing_version_names_the_field() {
+        let registry = Registry::new();
+        let meta = registry.publish("lib", "1.0.0");
+        let text = fs::read_to_string(&meta).unwrap();
+        fs::write(&meta, text.replace("version: 1.0.0\n", "")).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(metadata),
+        )
+        .await;
+        let req = TestRequest::get().uri("/packages/metadata/lib?v=1.0.0");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = read_body(res).await;
+        assert!(String::from_utf8_lossy(&body).contains("`version`"));
+    }
+
+    #[test]
+    fn missing_field_is_read_from_either_format() {
+        let registry = Registry::new();
+        let yaml = registry.publish("lib", "1.0.0");
+        let data = registry.data();
+        fs::write(&yaml, "name: lib\ndescription: d\n").unwrap();
+        assert_eq!(
+            missing_metadata_field(&yaml, &data).as_deref(),
+            Some("version")
+        );
+        let json = yaml.with_file_name("metadata.json");
+        fs::remove_file(&yaml).unwrap();
+        fs::write(
+            &json,
+            r#"{"name": "lib", "description": "d", "version": "1.0.0"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            missing_metadata_field(&json, &data).as_deref(),
+            Some("origin")
+        );
+        // Malformed rather than incomplete: no field to name.
+        fs::write(&json, "{").unwrap();
+        assert_eq!(missing_metadata_field(&json, &data), None);
+    }
+
+    #[test]
+    fn select_lowest_flips_the_pick() {
+        let registry = Registry::new();
+        for ver in ["1.0.0", "1.2.0", "1.5.3", "2.0.0"] {
+            registry.publish("lib", ver);
+        }
+        let data = registry.data();
+        let find = |ver, select| resolved(&data, "lib", ver, select);
+        assert_eq!(find(">=1.1, <2", Select::Lowest).as_deref(), Some("1.2.0"));
+        assert_eq!(find(">=1.1, <2", Select::Highest).as_deref(), Some("1.5.3"));
+        assert_eq!(find("1", Select::Lowest).as_deref(), Some("1.0.0"));
+        assert_eq!(find("^3", Select::Lowest), None);
+    }
+
+    #[actix_web::test]
+    async fn select_query_defaults_to_highest() {
+        let registry = Registry::new();
+        for ver in ["1.0.0", "1.2.0"] {
+            registry.publish("lib", ver);
+        }
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(metadata),
+        )
+        .await;
+        let picked = |uri: &str| {
+            let req = TestRequest::get().uri(uri).to_request();
+            async {
+                let res = call_service(&app, req).await;
+                res.headers().get("X-Resolved-Version").cloned()
+            }
+        };
+        assert_eq!(
+            picked("/packages/metadata/lib?v=^1").await.unwrap(),
+            "1.2.0"
+        );
+        assert_eq!(
+            picked("/packages/metadata/lib?v=^1&select=lowest")
+                .await
+                .unwrap(),
+            "1.0.0"
+        );
+    }
+
+    #[actix_web::test]
+    async fn trailing_and_repeated_slashes_reach_the_same_route() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0");
+        let data = web::Data::new(registry.data());
+        let status = |mode: TrailingSlash, uri: &'static str| {
+            let data = data.clone();
+            async move {
+                let app = init_service(
+                    App::new()
+                        .app_data(data)
+                        .wrap(NormalizePath::new(mode))
+                        .service(metadata)
+                        .service(metadata_version),
+                )
+                .await;
+                let req = TestRequest::get().uri(uri).to_request();
+                call_service(&app, req).await.status()
+            }
+        };
+        for uri in [
+            "/packages/metadata/lib",
+            "/packages/metadata/lib/",
+            "//packages//metadata/lib",
+            "/packages/metadata/lib/1.0.0/",
+            "/packages/metadata/lib//1.0.0",
+        ] {
+            assert_eq!(
+                status(TrailingSlash::Trim, uri).await,
+                StatusCode::OK,
+                "{uri}"
+            );
+        }
+        // Merging leaves a trailing slash in place, so it no longer matches.
+        assert_eq!(
+            status(TrailingSlash::MergeOnly, "//packages/metadata/lib").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(TrailingSlash::MergeOnly, "/packages/metadata/lib/").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn exact_pin_excludes_builds() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.2.3+linux");
+        let data = registry.data();
+        let find = |ver| resolved(&data, "lib", ver, Select::Highest);
+        assert_eq!(find("1.2.3").as_deref(), Some("1.2.3+linux"));
+        assert_eq!(find("=1.2.3"), None);
+        registry.publish("lib", "1.2.3");
+        assert_eq!(find("=1.2.3").as_deref(), Some("1.2.3"));
+        // Once the plain directory exists it wins over its builds.
+        assert_eq!(find("1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(find("=1.2.3+linux").as_deref(), Some("1.2.3+linux"));
+    }
+
+    #[actix_web::test]
+    async fn package_without_artifact_is_not_found() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(package),
+        )
+        .await;
+        let req = TestRequest::get().uri("/package/lib/1.0.0");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body = read_body(res).await;
+        assert!(String::from_utf8_lossy(&body).contains("artifact"));
+    }
+
+    #[test]
+    fn version_param_aliases_v() {
+        let query = |q: &str| web::Query::<Version>::from_query(q).unwrap().into_inner();
+        assert_eq!(query("version=1.2.3").requested(), Some("1.2.3"));
+        assert_eq!(query("v=1.2.3").requested(), Some("1.2.3"));
+        assert_eq!(query("v=2&version=1.2.3").requested(), Some("2"));
+        assert_eq!(query("").requested(), None);
+    }
+
+    #[actix_web::test]
+    async fn version_param_selects_the_version() {
+        let registry = Registry::new();
+        for ver in ["1.2.3", "2.0.0"] {
+            registry.publish("lib", ver);
+        }
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(metadata),
+        )
+        .await;
+        let req = TestRequest::get().uri("/packages/metadata/lib?version=1.2.3");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.headers().get("X-Resolved-Version").unwrap(), "1.2.3");
+    }
+
+    #[test]
+    fn ci_prerelease_ignores_identifier_case() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0-RC1");
+        let mut data = registry.data();
+        assert_eq!(resolved(&data, "lib", "1.0.0-rc1", Select::Highest), None);
+        data.ci_prerelease = true;
+        assert_eq!(
+            resolved(&data, "lib", "1.0.0-rc1", Select::Highest).as_deref(),
+            Some("1.0.0-RC1")
+        );
+        assert_eq!(
+            resolved(&data, "lib", "1.0.0-RC1", Select::Highest).as_deref(),
+            Some("1.0.0-RC1")
+        );
+    }
+
+    #[actix_web::test]
+    async fn latest_redirect_encodes_the_location() {
+        let registry = Registry::new();
+        registry.publish("my café", "1.0.0+b 1");
+        let mut data = registry.data();
+        data.redirect_latest = true;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(data))
+                .service(metadata_latest)
+                .service(package),
+        )
+        .await;
+        let location = |uri: &str| {
+            let req = TestRequest::get().uri(uri).to_request();
+            async {
+                call_service(&app, req)
+                    .await
+                    .headers()
+                    .get(header::LOCATION)
+                    .cloned()
+            }
+        };
+        assert_eq!(
+            location("/packages/metadata/my%20caf%C3%A9/latest?fields=name")
+                .await
+                .unwrap(),
+            "/packages/metadata/my%20caf%C3%A9?fields=name&v=1.0.0%2Bb%201"
+        );
+        assert_eq!(
+            location("/package/my%20caf%C3%A9/latest").await.unwrap(),
+            "/package/my%20caf%C3%A9/1.0.0%2Bb%201"
+        );
+    }
+
+    #[actix_web::test]
+    async fn multiple_choices_can_be_followed() {
+        let registry = Registry::new();
+        let dir = registry.publish("lib", "1.0.0");
+        let dir = dir.parent().unwrap();
+        fs::write(dir.join("lib-linux.pax"), "linux").unwrap();
+        fs::write(dir.join("lib win.pax"), "win").unwrap();
+        fs::write(dir.join("notes.txt"), "private").unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(package)
+                .service(package_file),
+        )
+        .await;
+        let req = TestRequest::get().uri("/package/lib/1.0.0");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::MULTIPLE_CHOICES);
+        let choices: Vec<String> = serde_json::from_slice(&read_body(res).await).unwrap();
+        assert_eq!(
+            choices,
+            [
+                "http://localhost:8080/package/lib/1.0.0/files/lib%20win.pax",
+                "http://localhost:8080/package/lib/1.0.0/files/lib-linux.pax",
+            ]
+        );
+        let path = |x: &str| x.trim_start_matches("http://localhost:8080").to_string();
+        let req = TestRequest::get().uri(&path(&choices[0]));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res).await, "win");
+        for uri in [
+            "/package/lib/1.0.0/files/notes.txt",
+            "/package/lib/1.0.0/files/metadata.yaml",
+            "/package/lib/1.0.0/files/..%2F..%2Flib-1.0.0.pax",
+        ] {
+            let req = TestRequest::get().uri(uri);
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+
+    // Every metadata this fixture publishes lacks `license`.
+    fn requiring_license(registry: &Registry) -> CoreData {
+        let schema = json!({ "type": "object", "required": ["license"] });
+        CoreData {
+            metadata_schema: Some(Arc::new(jsonschema::validator_for(&schema).unwrap())),
+            ..registry.data()
+        }
+    }
+
+    #[actix_web::test]
+    async fn channels_are_held_to_the_schema() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(requiring_license(&registry)))
+                .service(metadata_channels),
+        )
+        .await;
+        let req = TestRequest::get().uri("/packages/metadata/lib/channels");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn resolve_deps_hides_acl_protected_dependencies() {
+        let registry = Registry::new();
+        registry.publish("dep", "1.0.0");
+        fs::write(registry.0.join("dep/.acl"), "secret\n").unwrap();
+        let data = CoreData {
+            enable_acls: true,
+            ..registry.data()
+        };
+        let resolved = |headers: &HeaderMap| {
+            resolve_dependency("dep ^1", headers, &data).unwrap()["resolved"].clone()
+        };
+        assert_eq!(resolved(&HeaderMap::new()), Value::Null);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            header::HeaderValue::from_static("Bearer secret"),
+        );
+        assert_eq!(resolved(&headers), json!("1.0.0"));
+    }
+
+    #[actix_web::test]
+    async fn enveloped_metadata_skips_the_gzip_cache() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0");
+        let data = CoreData {
+            gzip_cache: Some(Arc::default()),
+            ..registry.data()
+        };
+        let app = init_service(App::new().app_data(web::Data::new(data)).service(metadata)).await;
+        let get = |api: &'static str| {
+            TestRequest::get()
+                .uri("/packages/metadata/lib?v=1.0.0")
+                .insert_header((header::ACCEPT_ENCODING, "gzip"))
+                .insert_header(("Accept-Version", api))
+                .to_request()
+        };
+        let res = call_service(&app, get("1")).await;
+        assert!(res.headers().contains_key(header::CONTENT_ENCODING));
+        let res = call_service(&app, get("2")).await;
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[actix_web::test]
+    async fn all_matching_is_held_to_the_schema() {
+        let registry = Registry::new();
+        registry.publish("lib", "1.0.0");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(requiring_license(&registry)))
+                .service(metadata),
+        )
+        .await;
+        let req = TestRequest::get().uri("/packages/metadata/lib?v=^1&all_matching=true");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_web::test]
+    async fn translated_metadata_revalidates_per_language() {
+        let registry = Registry::new();
+        let meta = registry.publish("lib", "1.0.0");
+        let mut text = fs::read_to_string(&meta).unwrap();
+        text.push_str("description_i18n:\n  de: Bibliothek\n  en: Library\n");
+        fs::write(&meta, text).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(registry.data()))
+                .service(metadata),
+        )
+        .await;
+        let get = |language: &'static str| {
+            TestRequest::get()
+                .uri("/packages/metadata/lib?v=1.0.0")
+                .insert_header((header::ACCEPT_LANGUAGE, language))
+        };
+        let res = call_service(&app, get("de").to_request()).await;
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+        let req = get("en").insert_header((header::IF_NONE_MATCH, etag.clone()));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers().get(header::ETAG), Some(&etag));
+
+        let req = get("de").insert_header((header::IF_NONE_MATCH, etag));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::VARY).unwrap(), VARY_LANGUAGE.1);
+        assert_eq!(res.headers().get(header::CONTENT_LANGUAGE).unwrap(), "de");
+    }
+}
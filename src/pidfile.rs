@@ -0,0 +1,48 @@
+use crate::logging;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// Removes the file again when dropped, i.e. once the server has shut down
+// cleanly. A crash leaves it behind, which the next start detects as stale.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        match fs::read_to_string(&path) {
+            Ok(old) => match old.trim().parse::<u32>() {
+                Ok(pid) if pid != std::process::id() && is_running(pid) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} belongs to running process {pid}", path.display()),
+                    ));
+                }
+                _ => logging::warn(format!("Replacing stale PID file {}", path.display())),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            logging::warn(format!(
+                "Failed to remove PID file {}: {e}",
+                self.path.display()
+            ));
+        }
+    }
+}
+
+// Only Linux exposes a cheap, dependency-free liveness check; elsewhere an
+// existing file is assumed stale and replaced.
+fn is_running(pid: u32) -> bool {
+    cfg!(target_os = "linux") && Path::new(&format!("/proc/{pid}")).exists()
+}
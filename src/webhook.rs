@@ -0,0 +1,46 @@
+use crate::logging;
+use actix_web::rt::{spawn, time::sleep};
+use awc::Client;
+use serde_json::json;
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const ATTEMPTS: u32 = 3;
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Fire-and-forget: delivery runs on its own task so a slow or unreachable
+// webhook never holds up the download that triggered it.
+pub fn download_event(url: &str, name: &str, version: &str, client_ip: Option<IpAddr>) {
+    let url = url.to_string();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    let event = json!({
+        "name": name,
+        "version": version,
+        "client_ip": client_ip,
+        "timestamp": timestamp,
+    });
+    spawn(async move {
+        let client = Client::builder().timeout(SEND_TIMEOUT).finish();
+        for attempt in 1..=ATTEMPTS {
+            match client.post(&url).send_json(&event).await {
+                Ok(res) if res.status().is_success() => return,
+                Ok(res) => logging::warn(format!(
+                    "Webhook delivery attempt {attempt}/{ATTEMPTS} got {}",
+                    res.status()
+                )),
+                Err(e) => logging::warn(format!(
+                    "Webhook delivery attempt {attempt}/{ATTEMPTS} failed: {e}"
+                )),
+            }
+            if attempt < ATTEMPTS {
+                sleep(Duration::from_secs(1 << attempt)).await;
+            }
+        }
+        logging::error("Giving up on webhook delivery for a download event");
+    });
+}